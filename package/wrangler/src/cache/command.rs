@@ -0,0 +1,249 @@
+use crate::cache::{CacheEntry, CachedValue, FlakeCache};
+use crate::invocation::Invocation;
+use crate::nix::flake::{NixFlake, FLAKE_REF_MARKER};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const USAGE: &str = "usage: nix-rust-wrangler cache status|clear|prune [--flake PATH]";
+
+/// Entry point of `nix-rust-wrangler cache`.
+pub fn run_cache_command(invocation: Invocation) -> ! {
+    let mut args = invocation.remaining_args.into_iter();
+    let subcommand = args.next();
+
+    let mut flake_filter = None;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--flake") => match args.next() {
+                Some(path) => flake_filter = Some(path),
+                None => usage_error("--flake requires a path"),
+            },
+            _ => usage_error(&format!("unexpected argument {}", arg.to_string_lossy())),
+        }
+    }
+
+    let flake_filter = flake_filter.map(|path| resolve_flake(&path));
+
+    let result = match subcommand.as_ref().and_then(|v| v.to_str()) {
+        Some("status") => status(flake_filter.as_ref()),
+        Some("clear") => clear(flake_filter.as_ref()),
+        Some("prune") => prune(flake_filter.as_ref()),
+        Some(other) => usage_error(&format!("unknown cache command {}", other)),
+        None => usage_error("missing cache command"),
+    };
+
+    if let Err(err) = result {
+        tracing::error!("Cache command failed: {}", err);
+        std::process::exit(1);
+    }
+
+    std::process::exit(0);
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
+fn resolve_flake(path: &OsString) -> NixFlake {
+    let path = Path::new(path);
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    NixFlake::find(&path).unwrap_or_else(|| {
+        tracing::error!(
+            "No flake.nix or {} found at or above {}",
+            FLAKE_REF_MARKER,
+            path.display()
+        );
+        std::process::exit(1);
+    })
+}
+
+/// Collect the entries to operate on, either the one of the given flake or all.
+fn selected_entries(
+    flake_filter: Option<&NixFlake>,
+) -> std::io::Result<Vec<(PathBuf, Option<CacheEntry>)>> {
+    match flake_filter {
+        Some(flake) => Ok(FlakeCache::load(flake)
+            .filter(|cache| cache.entry_path().exists())
+            .map(|cache| {
+                (
+                    cache.entry_path().to_path_buf(),
+                    Some(cache.entry().clone()),
+                )
+            })
+            .into_iter()
+            .collect()),
+        None => FlakeCache::list_entries(),
+    }
+}
+
+fn status(flake_filter: Option<&NixFlake>) -> std::io::Result<()> {
+    let entries = selected_entries(flake_filter)?;
+    if entries.is_empty() {
+        println!("No cache entries");
+        return Ok(());
+    }
+
+    for (path, entry) in entries {
+        let Some(entry) = entry else {
+            println!("{}: unreadable", path.display());
+            continue;
+        };
+
+        // Computing the current key tells whether results are still fresh
+        let current_key = flake_of_entry(&entry)
+            .and_then(|flake| FlakeCache::load(&flake))
+            .map(|cache| cache.key().to_string());
+
+        match &entry.reference {
+            Some(reference) => println!("{} (used by {})", reference, entry.flake.display()),
+            None => println!("{}", entry.flake.display()),
+        }
+        println!("  entry: {}", path.display());
+        match &current_key {
+            Some(key) => println!("  current key: {}", key),
+            None => println!("  current key: flake no longer exists"),
+        }
+
+        if let Some(inspection) = &entry.inspection {
+            println!(
+                "  evaluation: {}",
+                describe(inspection, current_key.as_deref())
+            );
+        }
+
+        if let Some(failure) = &entry.failure {
+            println!("  failure: {}", describe(failure, current_key.as_deref()));
+        }
+
+        for (name, toolchain) in &entry.last_good {
+            println!(
+                "  last good toolchain {}: {}, {} old{}",
+                if name.is_empty() { "(default)" } else { name },
+                toolchain.value.display(),
+                format_age(toolchain.age()),
+                if toolchain.value.exists() {
+                    ""
+                } else {
+                    " (missing)"
+                }
+            );
+        }
+
+        for (target, build) in &entry.builds {
+            println!(
+                "  build {}: {}",
+                target,
+                describe(build, current_key.as_deref())
+            );
+            for output in &build.value {
+                for (name, store_path) in &output.outputs {
+                    let missing = if store_path.exists() {
+                        ""
+                    } else {
+                        " (missing)"
+                    };
+                    println!("    {}: {}{}", name, store_path.display(), missing);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the flake a cache entry belongs to, if it still exists.
+fn flake_of_entry(entry: &CacheEntry) -> Option<NixFlake> {
+    match &entry.reference {
+        Some(reference) => NixFlake::from_reference(reference, &entry.flake),
+        None => NixFlake::find(&entry.flake)
+            .filter(|flake| flake.remote().is_none() && flake.dir() == entry.flake),
+    }
+}
+
+fn describe<T>(value: &CachedValue<T>, current_key: Option<&str>) -> String {
+    let state = if current_key == Some(value.key.as_str()) {
+        "fresh"
+    } else {
+        "stale"
+    };
+
+    format!(
+        "key {}, {}, {} old",
+        value.key,
+        state,
+        format_age(value.age())
+    )
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn clear(flake_filter: Option<&NixFlake>) -> std::io::Result<()> {
+    let mut removed = 0;
+    for (path, _) in selected_entries(flake_filter)? {
+        match std::fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    println!("Removed {} cache entries", removed);
+    Ok(())
+}
+
+fn prune(flake_filter: Option<&NixFlake>) -> std::io::Result<()> {
+    let mut removed_entries = 0;
+    let mut removed_results = 0;
+
+    for (path, entry) in selected_entries(flake_filter)? {
+        let cache = entry
+            .as_ref()
+            .and_then(flake_of_entry)
+            .and_then(|flake| FlakeCache::load(&flake));
+
+        let Some(mut cache) = cache else {
+            // Unreadable or the flake is gone
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed_entries += 1,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+            continue;
+        };
+
+        removed_results += cache.prune();
+
+        let entry = cache.entry();
+        if entry.inspection.is_none()
+            && entry.failure.is_none()
+            && entry.builds.is_empty()
+            && entry.last_good.is_empty()
+        {
+            cache.remove()?;
+            removed_entries += 1;
+        }
+    }
+
+    let removed_leftovers = if flake_filter.is_none() {
+        FlakeCache::remove_leftovers()?
+    } else {
+        0
+    };
+
+    println!(
+        "Removed {} cache entries, {} stale results and {} leftover files",
+        removed_entries, removed_results, removed_leftovers
+    );
+    Ok(())
+}
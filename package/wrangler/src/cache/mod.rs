@@ -0,0 +1,754 @@
+use crate::nix::config::FlakeInspection;
+use crate::nix::flake::{BuildTarget, FlakeBuildOutput, NixFlake};
+use crate::util::StableHasher;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::hash::Hasher;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub mod command;
+
+/// How deep the flake directory is searched for files which influence the evaluation.
+const MAX_INPUT_SEARCH_DEPTH: usize = 4;
+
+/// How long results of remote flakes are used by default.
+const DEFAULT_REMOTE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Locks older than this are assumed to belong to a crashed process.
+const LOCK_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// Persistent, per-flake cache of evaluation and build results.
+///
+/// Every flake gets a single JSON entry in the cache directory. Results stored in the
+/// entry are tagged with the key they were computed for. The key is derived from the
+/// flake directory and the contents of all files which are likely to influence the
+/// evaluation (nix files, the lock file and rust-toolchain files). A result whose key
+/// differs from the current one is stale. Remote flakes are keyed by their reference
+/// and the time their entry was created, so that the results expire after a while.
+#[derive(Debug)]
+pub struct FlakeCache {
+    entry_path: PathBuf,
+    key: String,
+    entry: CacheEntry,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub flake: PathBuf,
+
+    /// Reference of the flake, if it is not a local flake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+
+    /// When the results of a remote flake started being collected. Once this is older
+    /// than the TTL, a new key is used and the previous results are stale.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+
+    #[serde(default)]
+    pub inspection: Option<CachedValue<serde_json::Value>>,
+
+    #[serde(default, with = "pairs")]
+    pub builds: BTreeMap<BuildTarget, CachedValue<Vec<FlakeBuildOutput>>>,
+
+    #[serde(default)]
+    pub failure: Option<CachedValue<CachedFailure>>,
+
+    /// Toolchain directories last resolved successfully, by toolchain override name. The
+    /// default toolchain uses an empty name.
+    #[serde(default)]
+    pub last_good: BTreeMap<String, CachedValue<PathBuf>>,
+
+    /// Set if nix only accepted a path: reference to the flake, because it uses files
+    /// which are not tracked by git.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_ref: Option<CachedValue<bool>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedFailure {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedValue<T> {
+    pub key: String,
+    pub created: u64,
+    pub value: T,
+}
+
+impl<T> CachedValue<T> {
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(crate::util::unix_time_now().saturating_sub(self.created))
+    }
+}
+
+#[derive(Debug)]
+pub enum CacheLookup<T> {
+    /// The value was computed for the current key.
+    Fresh(T),
+
+    /// The value was computed for an older key.
+    Stale {
+        value: T,
+        age: Duration,
+    },
+
+    Missing,
+}
+
+impl FlakeCache {
+    /// Open the cache entry for a flake.
+    ///
+    /// Returns `None` if caching has been disabled using NIX_RUST_WRANGLER_DISABLE_CACHE or
+    /// no cache directory could be determined.
+    pub fn open(flake: &NixFlake) -> Option<Self> {
+        if crate::util::flag_from_env("NIX_RUST_WRANGLER_DISABLE_CACHE") {
+            tracing::debug!("Cache disabled by NIX_RUST_WRANGLER_DISABLE_CACHE");
+            return None;
+        }
+
+        Self::load(flake)
+    }
+
+    /// Open the cache entry for a flake, even if caching is disabled.
+    pub fn load(flake: &NixFlake) -> Option<Self> {
+        let entry_path = Self::cache_dir()?.join(Self::entry_file_name(&flake.cache_identity()));
+
+        let entry = match std::fs::read(&entry_path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Discarding unreadable cache entry {}: {}",
+                    entry_path.display(),
+                    err
+                );
+                CacheEntry::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => CacheEntry::default(),
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to read cache entry {}: {}",
+                    entry_path.display(),
+                    err
+                );
+                CacheEntry::default()
+            }
+        };
+
+        let mut key = Self::compute_key(flake);
+
+        // Remote flakes can't be watched for changes, so their results expire instead
+        let created = flake.remote().map(|_| {
+            let now = crate::util::unix_time_now();
+            let created = entry
+                .created
+                .filter(|created| now.saturating_sub(*created) < Self::remote_ttl())
+                .unwrap_or(now);
+
+            let mut hasher = StableHasher::new();
+            hasher.write(key.as_bytes());
+            hasher.write(&created.to_le_bytes());
+            key = hasher.finish_hex();
+
+            created
+        });
+
+        tracing::debug!("Cache key for {} is {}", flake.dir().display(), key);
+
+        Some(Self {
+            entry_path,
+            key,
+            entry: CacheEntry {
+                flake: flake.dir().to_path_buf(),
+                reference: flake.remote().map(|remote| remote.to_string()),
+                created,
+                ..entry
+            },
+        })
+    }
+
+    /// Determine the directory where cache entries are stored.
+    ///
+    /// This is NIX_RUST_WRANGLER_CACHE_DIR if set, or a nix-rust-wrangler directory in the
+    /// XDG cache directory otherwise.
+    pub fn cache_dir() -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os("NIX_RUST_WRANGLER_CACHE_DIR").filter(|v| !v.is_empty())
+        {
+            return Some(PathBuf::from(dir));
+        }
+
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+
+        Some(base.join("nix-rust-wrangler"))
+    }
+
+    /// Read all entries in the cache directory.
+    ///
+    /// Entries which can't be parsed are returned as `None`.
+    pub fn list_entries() -> std::io::Result<Vec<(PathBuf, Option<CacheEntry>)>> {
+        let Some(dir) = Self::cache_dir() else {
+            return Ok(Vec::new());
+        };
+
+        let read_dir = match std::fs::read_dir(&dir) {
+            Ok(v) => v,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        for dir_entry in read_dir {
+            let path = dir_entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+
+            let entry = std::fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice(&data).ok());
+            entries.push((path, entry));
+        }
+
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    /// Remove leftovers of crashed processes from the cache directory.
+    pub fn remove_leftovers() -> std::io::Result<usize> {
+        let Some(dir) = Self::cache_dir() else {
+            return Ok(0);
+        };
+
+        let read_dir = match std::fs::read_dir(&dir) {
+            Ok(v) => v,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let mut removed = 0;
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name();
+            let name = name.as_bytes();
+
+            let is_leftover = name.ends_with(b".lock") || name.windows(5).any(|w| w == b".tmp.");
+            let expired = dir_entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|elapsed| elapsed > LOCK_EXPIRY);
+
+            if is_leftover && expired && std::fs::remove_file(dir_entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn entry(&self) -> &CacheEntry {
+        &self.entry
+    }
+
+    pub fn entry_path(&self) -> &Path {
+        &self.entry_path
+    }
+
+    /// Delete the entry of this flake.
+    ///
+    /// Concurrent invocations which have already read the entry keep working, and may
+    /// recreate it afterwards.
+    pub fn remove(self) -> std::io::Result<()> {
+        match std::fs::remove_file(&self.entry_path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop all stale results and builds whose store paths no longer exist.
+    ///
+    /// Returns the number of removed results.
+    pub fn prune(&mut self) -> usize {
+        let key = self.key.clone();
+        let mut removed = 0;
+
+        self.update(|entry| {
+            if entry.inspection.as_ref().is_some_and(|v| v.key != key) {
+                entry.inspection = None;
+                removed += 1;
+            }
+
+            if entry.failure.as_ref().is_some_and(|v| v.key != key) {
+                entry.failure = None;
+                removed += 1;
+            }
+
+            let builds_before = entry.builds.len();
+            entry.builds.retain(|_, build| {
+                build.key == key
+                    && build
+                        .value
+                        .iter()
+                        .flat_map(|output| output.outputs.values())
+                        .all(|path| path.exists())
+            });
+            removed += builds_before - entry.builds.len();
+
+            // Last known good toolchains are kept regardless of their key
+            let last_good_before = entry.last_good.len();
+            entry
+                .last_good
+                .retain(|_, toolchain| toolchain.value.exists());
+            removed += last_good_before - entry.last_good.len();
+
+            if entry.path_ref.as_ref().is_some_and(|v| v.key != key) {
+                entry.path_ref = None;
+                removed += 1;
+            }
+        });
+
+        removed
+    }
+
+    pub fn inspection(&self) -> CacheLookup<serde_json::Value> {
+        Self::lookup(&self.key, self.entry.inspection.as_ref())
+    }
+
+    pub fn store_inspection(&mut self, inspection: serde_json::Value) {
+        let value = self.new_value(inspection);
+        self.update(|entry| {
+            entry.inspection = Some(value);
+            entry.failure = None;
+        });
+    }
+
+    /// Look up a failed evaluation for the current key which happened within the window.
+    pub fn recent_failure(&self, window: Duration) -> Option<CachedValue<CachedFailure>> {
+        self.entry
+            .failure
+            .as_ref()
+            .filter(|failure| failure.key == self.key && failure.age() < window)
+            .cloned()
+    }
+
+    pub fn store_failure(&mut self, message: String) {
+        let value = self.new_value(CachedFailure { message });
+        self.update(|entry| entry.failure = Some(value));
+    }
+
+    pub fn build(&self, target: &BuildTarget) -> CacheLookup<Vec<FlakeBuildOutput>> {
+        let cached = self.entry.builds.get(target).filter(|cached| {
+            // The store paths may have been garbage collected in the meantime
+            cached
+                .value
+                .iter()
+                .flat_map(|output| output.outputs.values())
+                .all(|path| path.exists())
+        });
+
+        Self::lookup(&self.key, cached)
+    }
+
+    pub fn store_build(&mut self, target: &BuildTarget, outputs: Vec<FlakeBuildOutput>) {
+        let value = self.new_value(outputs);
+        self.update(|entry| {
+            entry.builds.insert(target.clone(), value);
+        });
+    }
+
+    /// The toolchain directory last resolved for a toolchain override, regardless of the
+    /// key, if it still exists in the store.
+    pub fn last_good(&self, name: &str) -> Option<CachedValue<PathBuf>> {
+        self.entry
+            .last_good
+            .get(name)
+            .filter(|toolchain| toolchain.value.exists())
+            .cloned()
+    }
+
+    pub fn store_last_good(&mut self, name: &str, toolchain_dir: &Path) {
+        let unchanged =
+            self.entry.last_good.get(name).is_some_and(|toolchain| {
+                toolchain.key == self.key && toolchain.value == toolchain_dir
+            });
+        if unchanged {
+            return;
+        }
+
+        let value = self.new_value(toolchain_dir.to_path_buf());
+        self.update(|entry| {
+            entry.last_good.insert(name.to_string(), value);
+        });
+    }
+
+    /// Whether the flake had to be referred to using a path: reference for the current key.
+    pub fn needs_path_ref(&self) -> bool {
+        self.entry
+            .path_ref
+            .as_ref()
+            .is_some_and(|path_ref| path_ref.key == self.key && path_ref.value)
+    }
+
+    pub fn store_path_ref(&mut self) {
+        let value = self.new_value(true);
+        self.update(|entry| entry.path_ref = Some(value));
+    }
+
+    /// Targets which have been built previously, regardless of whether they are stale.
+    pub fn cached_build_targets(&self) -> Vec<BuildTarget> {
+        self.entry.builds.keys().cloned().collect()
+    }
+
+    /// Try to acquire the revalidation lock of this entry.
+    ///
+    /// Returns `None` if another process is currently revalidating.
+    pub fn try_lock_revalidation(&self) -> Option<RevalidationLock> {
+        let lock_path = self.entry_path.with_extension("lock");
+
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&lock_path)
+            {
+                Ok(mut file) => {
+                    let _ = write!(file, "{}", std::process::id());
+                    return Some(RevalidationLock { path: lock_path });
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let expired = std::fs::metadata(&lock_path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .map(|elapsed| elapsed > LOCK_EXPIRY)
+                        .unwrap_or(false);
+
+                    if !expired {
+                        return None;
+                    }
+
+                    tracing::debug!("Removing expired lock {}", lock_path.display());
+                    let _ = std::fs::remove_file(&lock_path);
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to create lock {}: {}", lock_path.display(), err);
+                    return None;
+                }
+            }
+        }
+
+        None
+    }
+
+    fn lookup<T: Clone>(key: &str, cached: Option<&CachedValue<T>>) -> CacheLookup<T> {
+        match cached {
+            None => CacheLookup::Missing,
+            Some(cached) if cached.key == key => CacheLookup::Fresh(cached.value.clone()),
+            Some(cached) => CacheLookup::Stale {
+                value: cached.value.clone(),
+                age: cached.age(),
+            },
+        }
+    }
+
+    fn new_value<T>(&self, value: T) -> CachedValue<T> {
+        CachedValue {
+            key: self.key.clone(),
+            created: crate::util::unix_time_now(),
+            value,
+        }
+    }
+
+    /// Apply a modification to the entry and persist it.
+    ///
+    /// The entry is re-read before modifying it, so that results written by concurrent
+    /// invocations in the meantime are kept.
+    fn update(&mut self, modify: impl FnOnce(&mut CacheEntry)) {
+        if let Some(on_disk) = std::fs::read(&self.entry_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<CacheEntry>(&data).ok())
+        {
+            self.entry.inspection = on_disk.inspection;
+            self.entry.builds = on_disk.builds;
+            self.entry.failure = on_disk.failure;
+            self.entry.last_good = on_disk.last_good;
+            self.entry.path_ref = on_disk.path_ref;
+        }
+
+        modify(&mut self.entry);
+
+        if let Err(err) = Self::write_atomically(&self.entry_path, &self.entry) {
+            tracing::warn!(
+                "Failed to write cache entry {}: {}",
+                self.entry_path.display(),
+                err
+            );
+        }
+    }
+
+    /// Write the entry to a temporary file and move it into place, so that concurrent
+    /// readers never observe a partially written entry.
+    fn write_atomically(path: &Path, entry: &CacheEntry) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+        let result = serde_json::to_vec(entry)
+            .map_err(std::io::Error::other)
+            .and_then(|data| std::fs::write(&tmp_path, data))
+            .and_then(|_| std::fs::rename(&tmp_path, path));
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    fn entry_file_name(identity: &OsStr) -> String {
+        let mut hasher = StableHasher::new();
+        hasher.write(identity.as_bytes());
+        format!("flake-{}.json", hasher.finish_hex())
+    }
+
+    /// The key results for the flake are stored under, like [FlakeCache::key], but also
+    /// if caching is disabled. Only the keys of remote flakes depend on the entry.
+    pub fn current_key(flake: &NixFlake) -> String {
+        match flake.remote().and_then(|_| Self::open(flake)) {
+            Some(cache) => cache.key,
+            None => Self::compute_key(flake),
+        }
+    }
+
+    /// Compute the key identifying the current state of the flake.
+    ///
+    /// For remote flakes, [FlakeCache::load] extends this by the time the entry was created.
+    pub fn compute_key(flake: &NixFlake) -> String {
+        let mut hasher = StableHasher::new();
+        hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.write(FlakeInspection::APPLY_EXPR.as_bytes());
+        hasher.write(crate::nix::target_system().as_bytes());
+        hasher.write(
+            std::env::var_os("NIX_RUST_WRANGLER_NIX_ARGS")
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+
+        if let Some(remote) = flake.remote() {
+            hasher.write(remote.to_string().as_bytes());
+            return hasher.finish_hex();
+        }
+
+        hasher.write(flake.dir().as_os_str().as_bytes());
+
+        let mut inputs = Vec::new();
+        Self::collect_input_files(flake.dir(), 0, &mut inputs);
+        inputs.sort();
+
+        for input in inputs {
+            hasher.write(input.as_os_str().as_bytes());
+            match std::fs::read(&input) {
+                Ok(data) => hasher.write(&data),
+                Err(err) => {
+                    tracing::debug!("Failed to read {} for cache key: {}", input.display(), err);
+                }
+            }
+        }
+
+        hasher.finish_hex()
+    }
+
+    /// How long results of remote flakes are used, configured using
+    /// NIX_RUST_WRANGLER_REMOTE_CACHE_TTL in seconds.
+    fn remote_ttl() -> u64 {
+        std::env::var("NIX_RUST_WRANGLER_REMOTE_CACHE_TTL")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_REMOTE_TTL.as_secs())
+            .max(1)
+    }
+
+    /// Collect the files which may influence the flake evaluation.
+    fn collect_input_files(dir: &Path, depth: usize, inputs: &mut Vec<PathBuf>) {
+        let Ok(read_dir) = std::fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in read_dir.flatten() {
+            let name = entry.file_name();
+            let name = name.as_bytes();
+
+            // Symlinks are not followed, this skips nix result links
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+
+            if file_type.is_dir() {
+                if depth < MAX_INPUT_SEARCH_DEPTH
+                    && !name.starts_with(b".")
+                    && name != b"target"
+                    && name != b"node_modules"
+                {
+                    Self::collect_input_files(&entry.path(), depth + 1, inputs);
+                }
+            } else if file_type.is_file()
+                && (name.ends_with(b".nix")
+                    || name == b"flake.lock"
+                    || name == b"rust-toolchain"
+                    || name == b"rust-toolchain.toml")
+            {
+                inputs.push(entry.path());
+            }
+        }
+    }
+}
+
+/// Lock held by the process revalidating a cache entry, released on drop.
+#[derive(Debug)]
+pub struct RevalidationLock {
+    path: PathBuf,
+}
+
+impl Drop for RevalidationLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Serialize maps whose keys are not strings as lists of key-value pairs, as JSON objects
+/// only allow string keys.
+mod pairs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Vec::<(K, V)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached<T>(key: &str, age: u64, value: T) -> CachedValue<T> {
+        CachedValue {
+            key: key.to_string(),
+            created: crate::util::unix_time_now() - age,
+            value,
+        }
+    }
+
+    #[test]
+    fn lookup_distinguishes_fresh_and_stale_values() {
+        assert!(matches!(
+            FlakeCache::lookup(
+                "current",
+                Some(&cached("current", 0, "evaluation".to_string()))
+            ),
+            CacheLookup::Fresh(value) if value == "evaluation"
+        ));
+
+        match FlakeCache::lookup("current", Some(&cached("previous", 30, 1))) {
+            CacheLookup::Stale { value, age } => {
+                assert_eq!(value, 1);
+                assert!(age >= Duration::from_secs(30));
+            }
+            other => panic!("expected a stale value, got {:?}", other),
+        }
+
+        assert!(matches!(
+            FlakeCache::lookup::<u32>("current", None),
+            CacheLookup::Missing
+        ));
+    }
+
+    #[test]
+    fn recent_failure_requires_current_key_within_window() {
+        let cache = |key: &str, age: u64| FlakeCache {
+            entry_path: PathBuf::from("/nonexistent/entry.json"),
+            key: "current".to_string(),
+            entry: CacheEntry {
+                failure: Some(cached(
+                    key,
+                    age,
+                    CachedFailure {
+                        message: "error: syntax error".to_string(),
+                    },
+                )),
+                ..CacheEntry::default()
+            },
+        };
+        let window = Duration::from_secs(10);
+
+        let failure = cache("current", 2).recent_failure(window).unwrap();
+        assert_eq!(failure.value.message, "error: syntax error");
+
+        assert!(cache("current", 20).recent_failure(window).is_none());
+        assert!(cache("previous", 2).recent_failure(window).is_none());
+        assert!(FlakeCache {
+            entry: CacheEntry::default(),
+            ..cache("current", 2)
+        }
+        .recent_failure(window)
+        .is_none());
+    }
+
+    #[test]
+    fn entry_builds_roundtrip_as_pairs() {
+        let target = BuildTarget::Applied {
+            builder: "rustWrangler.mkToolchain".into(),
+            argument: "rustWrangler.toolchains.nightly".into(),
+        };
+        let output = FlakeBuildOutput {
+            drv_path: "/nix/store/toolchain.drv".into(),
+            outputs: [("out".to_string(), PathBuf::from("/nix/store/toolchain"))].into(),
+        };
+
+        let mut entry = CacheEntry::default();
+        entry.builds.insert(
+            target.clone(),
+            CachedValue {
+                key: "key".into(),
+                created: 0,
+                value: vec![output],
+            },
+        );
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert!(json["builds"].is_array());
+
+        let entry = serde_json::from_value::<CacheEntry>(json).unwrap();
+        assert_eq!(entry.builds.keys().collect::<Vec<_>>(), vec![&target]);
+        assert_eq!(
+            entry.builds[&target].value[0].outputs["out"],
+            Path::new("/nix/store/toolchain")
+        );
+    }
+}
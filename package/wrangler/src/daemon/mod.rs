@@ -0,0 +1,125 @@
+use crate::nix::flake::{BuildTarget, FlakeBuildOutput};
+use crate::nix::process::timeout_from_env;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub mod server;
+
+/// Resolution request sent by the shims, one per connection.
+///
+/// The key is the cache key computed by the shim. The daemon only answers if it computes
+/// the same key, which guarantees that both see the same flake and configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DaemonRequest {
+    Inspect {
+        flake: PathBuf,
+        key: String,
+    },
+    Build {
+        flake: PathBuf,
+        key: String,
+        target: BuildTarget,
+    },
+}
+
+impl DaemonRequest {
+    /// How long the shim waits for the answer, which is as long as it would give nix
+    /// when resolving itself.
+    fn timeout(&self) -> Option<Duration> {
+        match self {
+            Self::Inspect { .. } => timeout_from_env("NIX_RUST_WRANGLER_EVAL_TIMEOUT_SECS"),
+            Self::Build { .. } => timeout_from_env("NIX_RUST_WRANGLER_BUILD_TIMEOUT_SECS"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DaemonResponse {
+    Inspection {
+        value: serde_json::Value,
+    },
+    Build {
+        outputs: Vec<FlakeBuildOutput>,
+    },
+
+    /// The daemon sees a different state of the flake, the shim has to resolve itself.
+    KeyMismatch,
+    Error {
+        message: String,
+    },
+}
+
+/// Determine the path of the daemon socket.
+///
+/// This is NIX_RUST_WRANGLER_DAEMON_SOCKET if set, a socket in XDG_RUNTIME_DIR or, as a
+/// last resort, a socket in the cache directory.
+pub fn socket_path() -> Option<PathBuf> {
+    if let Some(path) =
+        std::env::var_os("NIX_RUST_WRANGLER_DAEMON_SOCKET").filter(|v| !v.is_empty())
+    {
+        return Some(PathBuf::from(path));
+    }
+
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|v| !v.is_empty()) {
+        return Some(Path::new(&runtime_dir).join("nix-rust-wrangler/daemon.sock"));
+    }
+
+    crate::cache::FlakeCache::cache_dir().map(|dir| dir.join("daemon.sock"))
+}
+
+/// Send a request to the daemon.
+///
+/// Returns `None` if the daemon is disabled, not running or the communication failed, in
+/// which case the caller resolves in-process.
+pub fn query(request: &DaemonRequest) -> Option<DaemonResponse> {
+    if crate::util::flag_from_env("NIX_RUST_WRANGLER_DISABLE_DAEMON") {
+        return None;
+    }
+
+    let path = socket_path()?;
+    if !path.exists() {
+        return None;
+    }
+
+    match query_at(&path, request) {
+        Ok(v) => Some(v),
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) =>
+        {
+            tracing::warn!(
+                "Daemon at {} did not answer in time, resolving without it",
+                path.display()
+            );
+            None
+        }
+        Err(err) => {
+            tracing::debug!("Daemon at {} not available: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+fn query_at(path: &Path, request: &DaemonRequest) -> std::io::Result<DaemonResponse> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(request.timeout())?;
+    stream.set_write_timeout(request.timeout())?;
+
+    let mut data = serde_json::to_vec(request)?;
+    data.push(b'\n');
+    stream.write_all(&data)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    serde_json::from_slice(&response).map_err(Into::into)
+}
@@ -0,0 +1,305 @@
+use crate::cache::FlakeCache;
+use crate::daemon::{DaemonRequest, DaemonResponse};
+use crate::nix::evaluator::FlakeEvaluator;
+use crate::nix::flake::{BuildTarget, FlakeBuildOutput, NixFlake};
+use crate::nix::NixCommand;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the watched flakes are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// In-memory resolutions of a single flake.
+#[derive(Debug)]
+struct FlakeState {
+    flake: NixFlake,
+    key: String,
+    inspection: Option<serde_json::Value>,
+    builds: HashMap<BuildTarget, Vec<FlakeBuildOutput>>,
+}
+
+impl FlakeState {
+    fn new(flake: NixFlake, key: String) -> Self {
+        Self {
+            flake,
+            key,
+            inspection: None,
+            builds: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Daemon {
+    nix_command: NixCommand,
+    flakes: Mutex<HashMap<PathBuf, FlakeState>>,
+}
+
+/// Entry point of `nix-rust-wrangler daemon`.
+pub fn run_daemon(nix_command: Option<NixCommand>) -> ! {
+    let Some(nix_command) = nix_command else {
+        tracing::error!("The daemon requires nix to be available");
+        std::process::exit(1);
+    };
+
+    let Some(socket_path) = crate::daemon::socket_path() else {
+        tracing::error!("Unable to determine the daemon socket path");
+        std::process::exit(1);
+    };
+
+    let listener = match bind(&socket_path) {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::error!("Failed to listen on {}: {}", socket_path.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("Daemon listening on {}", socket_path.display());
+
+    let daemon = Arc::new(Daemon {
+        nix_command,
+        flakes: Mutex::new(HashMap::new()),
+    });
+
+    {
+        let daemon = daemon.clone();
+        std::thread::spawn(move || daemon.watch());
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let daemon = daemon.clone();
+                std::thread::spawn(move || daemon.handle_connection(stream));
+            }
+            Err(err) => tracing::warn!("Failed to accept connection: {}", err),
+        }
+    }
+
+    std::process::exit(0);
+}
+
+/// Bind the socket, replacing a stale socket of a daemon which is no longer running.
+fn bind(socket_path: &Path) -> std::io::Result<UnixListener> {
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another daemon is already running",
+            ));
+        }
+
+        tracing::debug!("Removing stale socket {}", socket_path.display());
+        std::fs::remove_file(socket_path)?;
+    }
+
+    if let Some(parent) = socket_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+impl Daemon {
+    fn handle_connection(&self, stream: UnixStream) {
+        let mut line = String::new();
+        if let Err(err) = BufReader::new(&stream).read_line(&mut line) {
+            tracing::warn!("Failed to read request: {}", err);
+            return;
+        }
+
+        let response = match serde_json::from_str::<DaemonRequest>(&line) {
+            Ok(request) => {
+                tracing::debug!("Handling request: {:?}", request);
+                self.handle_request(request)
+            }
+            Err(err) => DaemonResponse::Error {
+                message: format!("invalid request: {}", err),
+            },
+        };
+
+        let result = serde_json::to_vec(&response)
+            .map_err(std::io::Error::other)
+            .and_then(|data| (&stream).write_all(&data));
+
+        if let Err(err) = result {
+            tracing::warn!("Failed to send response: {}", err);
+        }
+    }
+
+    fn handle_request(&self, request: DaemonRequest) -> DaemonResponse {
+        let (flake_path, key) = match &request {
+            DaemonRequest::Inspect { flake, key } | DaemonRequest::Build { flake, key, .. } => {
+                (flake, key)
+            }
+        };
+
+        let Some(flake) = flake_path
+            .parent()
+            .and_then(NixFlake::find)
+            .filter(|flake| flake.path() == flake_path)
+        else {
+            return DaemonResponse::Error {
+                message: format!("no flake at {}", flake_path.display()),
+            };
+        };
+
+        let current_key = FlakeCache::compute_key(&flake);
+        if current_key != *key {
+            return DaemonResponse::KeyMismatch;
+        }
+
+        // Answer from memory if possible
+        {
+            let mut flakes = self.flakes.lock().unwrap();
+            let state = flakes
+                .entry(flake_path.clone())
+                .or_insert_with(|| FlakeState::new(flake.clone(), current_key.clone()));
+
+            if state.key != current_key {
+                *state = FlakeState::new(flake.clone(), current_key.clone());
+            }
+
+            match &request {
+                DaemonRequest::Inspect { .. } => {
+                    if let Some(value) = &state.inspection {
+                        return DaemonResponse::Inspection {
+                            value: value.clone(),
+                        };
+                    }
+                }
+                DaemonRequest::Build { target, .. } => {
+                    if let Some(outputs) = state.builds.get(target) {
+                        // The store paths may have been garbage collected in the meantime
+                        let realised = outputs
+                            .iter()
+                            .flat_map(|output| output.outputs.values())
+                            .all(|path| path.exists());
+
+                        if realised {
+                            return DaemonResponse::Build {
+                                outputs: outputs.clone(),
+                            };
+                        }
+
+                        tracing::debug!("Outputs of {} are gone, building again", target);
+                        state.builds.remove(target);
+                    }
+                }
+            }
+        }
+
+        let evaluator = FlakeEvaluator::new(&self.nix_command, &flake).in_process();
+        let response = match &request {
+            DaemonRequest::Inspect { .. } => evaluator
+                .inspect_value()
+                .map(|value| DaemonResponse::Inspection { value }),
+            DaemonRequest::Build { target, .. } => evaluator
+                .build(target)
+                .map(|outputs| DaemonResponse::Build { outputs }),
+        };
+
+        let response = match response {
+            Ok(v) => v,
+            Err(err) => {
+                return DaemonResponse::Error {
+                    message: err.to_string(),
+                }
+            }
+        };
+
+        let mut flakes = self.flakes.lock().unwrap();
+        if let Some(state) = flakes
+            .get_mut(flake_path)
+            .filter(|state| state.key == current_key)
+        {
+            match (&request, &response) {
+                (DaemonRequest::Build { target, .. }, DaemonResponse::Build { outputs }) => {
+                    state.builds.insert(target.clone(), outputs.clone());
+                }
+                (_, DaemonResponse::Inspection { value }) => {
+                    state.inspection = Some(value.clone());
+                }
+                _ => {}
+            }
+        }
+
+        response
+    }
+
+    /// Periodically check known flakes for changes and re-resolve them eagerly, so that
+    /// the next request can be answered from memory.
+    fn watch(&self) {
+        loop {
+            std::thread::sleep(WATCH_INTERVAL);
+
+            let known = self
+                .flakes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(path, state)| {
+                    (
+                        path.clone(),
+                        state.flake.clone(),
+                        state.key.clone(),
+                        state.builds.keys().cloned().collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            for (path, flake, key, targets) in known {
+                if !flake.path().is_file() {
+                    tracing::info!("Flake {} disappeared, forgetting it", path.display());
+                    self.flakes.lock().unwrap().remove(&path);
+                    continue;
+                }
+
+                let current_key = FlakeCache::compute_key(&flake);
+                if current_key == key {
+                    continue;
+                }
+
+                tracing::info!("Flake {} changed, re-resolving", path.display());
+                let state = self.resolve(flake, current_key, &targets);
+                self.flakes.lock().unwrap().insert(path, state);
+            }
+        }
+    }
+
+    fn resolve(&self, flake: NixFlake, key: String, targets: &[BuildTarget]) -> FlakeState {
+        let evaluator = FlakeEvaluator::new(&self.nix_command, &flake).in_process();
+
+        let inspection = evaluator
+            .inspect_value()
+            .map_err(|err| tracing::warn!("Failed to evaluate {}: {}", flake.path().display(), err))
+            .ok();
+
+        let builds = targets
+            .iter()
+            .filter_map(|target| match evaluator.build(target) {
+                Ok(outputs) => Some((target.clone(), outputs)),
+                Err(err) => {
+                    tracing::warn!("Failed to build {}: {}", target, err);
+                    None
+                }
+            })
+            .collect();
+
+        FlakeState {
+            flake,
+            key,
+            inspection,
+            builds,
+        }
+    }
+}
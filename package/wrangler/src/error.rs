@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -28,6 +29,18 @@ pub enum Error {
     
     #[error("unable to determine own executable: {0}")]
     UnableToDetermineOwnExecutable(std::io::Error),
+
+    #[error("nix is not usable and nix-shell could not be found")]
+    NixShellNotFound,
+
+    #[error("invalid flake configuration, set strict = false to ignore:\n  {}", issues.join("\n  "))]
+    InvalidConfig { issues: Vec<String> },
+
+    #[error("toolchain aliases form a cycle: {}", chain.join(" -> "))]
+    AliasCycle { chain: Vec<String> },
+
+    #[error("toolchain alias '{alias}' refers to '{target}', which is neither provided by the flake nor installed in the collection")]
+    UnknownAliasTarget { alias: String, target: String },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -42,6 +55,15 @@ pub enum FlakeEvalError {
         stderr: String,
     },
 
+    #[error("nix evaluation failed {}s ago and will not be retried yet:\n{message}", age.as_secs())]
+    CachedFailure {
+        age: Duration,
+        message: String,
+    },
+
+    #[error("nix did not finish within {}s and has been stopped", timeout.as_secs())]
+    Timeout { timeout: Duration },
+
     #[error(transparent)]
     Parse(#[from] serde_json::Error),
     
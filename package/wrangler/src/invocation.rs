@@ -1,6 +1,6 @@
 use crate::error::Error;
 use std::ffi::OsString;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum InvokedTool {
@@ -20,6 +20,9 @@ pub enum InvokedTool {
     Rustup,
     NixRustWrangler,
     NixDevelopProxy,
+    NixRevalidateCache,
+    Cache,
+    Daemon,
     Other(OsString),
 }
 
@@ -42,6 +45,9 @@ impl InvokedTool {
             Self::Rustup => "rustup".into(),
             Self::NixRustWrangler => "nix-rust-wrangler".into(),
             Self::NixDevelopProxy => "nix-develop-proxy".into(),
+            Self::NixRevalidateCache => "nix-revalidate-cache".into(),
+            Self::Cache => "cache".into(),
+            Self::Daemon => "daemon".into(),
             Self::Other(o) => o.clone(),
         }
     }
@@ -64,6 +70,9 @@ impl InvokedTool {
             Self::Rustup => "rustup".into(),
             Self::NixRustWrangler => "nix-rust-wrangler".into(),
             Self::NixDevelopProxy => "nix-develop-proxy".into(),
+            Self::NixRevalidateCache => "nix-revalidate-cache".into(),
+            Self::Cache => "cache".into(),
+            Self::Daemon => "daemon".into(),
             Self::Other(o) => o.to_string_lossy().into_owned(),
         }
     }
@@ -73,6 +82,10 @@ impl InvokedTool {
 pub struct Invocation {
     pub tool: InvokedTool,
     pub toolchain_override: ToolchainOverride,
+
+    /// Toolchain a parent invocation selected for its directory, which is used in place
+    /// of the directory selection of nested invocations.
+    pub directory_toolchain: Option<String>,
     pub remaining_args: Vec<OsString>,
 }
 
@@ -90,6 +103,15 @@ impl ToolchainOverride {
             Self::FromEnv(name) | Self::FromArg(name) => Some(name),
         }
     }
+
+    /// The same kind of override, selecting another toolchain.
+    pub fn with_name(&self, name: impl Into<String>) -> Self {
+        match self {
+            Self::None => Self::None,
+            Self::FromEnv(_) => Self::FromEnv(name.into()),
+            Self::FromArg(_) => Self::FromArg(name.into()),
+        }
+    }
 }
 
 impl Invocation {
@@ -110,7 +132,7 @@ impl Invocation {
             if let Some(toolchain_name) = next.to_str().and_then(|s| s.strip_prefix('+')) {
                 toolchain_override = ToolchainOverride::FromArg(toolchain_name.to_string());
             } else if tool == InvokedTool::NixRustWrangler {
-                tool = Self::self_arg_to_tool(next)?;
+                tool = Self::subcommand_or_tool(next)?;
             } else {
                 remaining_args.push(next);
             }
@@ -135,13 +157,74 @@ impl Invocation {
             toolchain_override = Self::toolchain_override_from_env("RUSTUP_TOOLCHAIN")?;
         }
 
+        let directory_toolchain = std::env::var("NIX_RUST_WRANGLER_DIRECTORY_TOOLCHAIN")
+            .ok()
+            .filter(|v| !v.is_empty());
+
         Ok(Self {
             tool,
             toolchain_override,
+            directory_toolchain,
             remaining_args,
         })
     }
 
+    /// Name of the toolchain selected by an override or for the directory of a parent
+    /// invocation.
+    pub fn toolchain_name(&self) -> Option<&str> {
+        self.toolchain_override
+            .as_override_name()
+            .or(self.directory_toolchain.as_deref())
+    }
+
+    /// The directory the invoked tool works on.
+    ///
+    /// This is the directory of the manifest passed to cargo via `--manifest-path`, or
+    /// the current working directory otherwise.
+    pub fn target_dir(&self) -> Option<PathBuf> {
+        let cwd = std::env::current_dir().ok()?;
+
+        let accepts_manifest_path = matches!(
+            self.tool,
+            InvokedTool::Cargo
+                | InvokedTool::CargoClippy
+                | InvokedTool::CargoFmt
+                | InvokedTool::CargoMiri
+        );
+        if !accepts_manifest_path {
+            return Some(cwd);
+        }
+
+        let mut args = self
+            .remaining_args
+            .iter()
+            .take_while(|arg| arg.as_os_str() != "--");
+        while let Some(arg) = args.next() {
+            let manifest_path = if arg == "--manifest-path" {
+                args.next().map(PathBuf::from)
+            } else {
+                arg.to_str()
+                    .and_then(|v| v.strip_prefix("--manifest-path="))
+                    .map(PathBuf::from)
+            };
+
+            if let Some(dir) = manifest_path.as_deref().and_then(Path::parent) {
+                return Some(cwd.join(dir));
+            }
+        }
+
+        Some(cwd)
+    }
+
+    /// Management commands are only available when invoked as nix-rust-wrangler directly.
+    fn subcommand_or_tool(arg: OsString) -> Result<InvokedTool, Error> {
+        match arg.to_str() {
+            Some("cache") => Ok(InvokedTool::Cache),
+            Some("daemon") => Ok(InvokedTool::Daemon),
+            _ => Self::self_arg_to_tool(arg),
+        }
+    }
+
     fn self_arg_to_tool(self_arg: OsString) -> Result<InvokedTool, Error> {
         let path = Path::new(&self_arg);
 
@@ -164,6 +247,7 @@ impl Invocation {
                 "rustup" => InvokedTool::Rustup,
                 "nix-rust-wrangler" => InvokedTool::NixRustWrangler,
                 "nix-develop-proxy" => InvokedTool::NixDevelopProxy,
+                "nix-revalidate-cache" => InvokedTool::NixRevalidateCache,
                 _ => InvokedTool::Other(tool_name.into()),
             })
         } else {
@@ -14,6 +14,9 @@ mod external;
 enum Inner {
     Internal,
     External(ExternalInvoker),
+
+    /// External command which already contains the arguments of the invocation.
+    ExternalWithArgs(ExternalInvoker),
 }
 
 #[derive(Debug)]
@@ -26,15 +29,20 @@ impl ToolInvoker {
     /// Create the invoker from the toolchain directory.
     ///
     /// This searches for the tool specified in the invocation inside a bin directory.
+    ///
+    /// If a resolution key is given, the toolchain directory is handed off to nested
+    /// invocations, which then skip resolving the toolchain again if they compute the
+    /// same key.
     pub fn from_toolchain_dir(
         toolchain_dir: &Path,
         invocation: &Invocation,
+        resolution_key: Option<&str>,
     ) -> Result<Self, Error> {
         ExternalInvoker::new_at(
             &toolchain_dir.join("bin"),
             invocation.tool.to_executable_name(),
         )
-        .map(|invoker| Self::from_external_invoker(invoker, Some(toolchain_dir)))
+        .map(|invoker| Self::from_external_invoker(invoker, Some(toolchain_dir), resolution_key))
         .ok_or_else(|| Error::ToolchainDoesNotProvideTool {
             path: toolchain_dir.to_path_buf(),
             tool: invocation.tool.to_name(),
@@ -45,17 +53,50 @@ impl ToolInvoker {
         Self::from_external_invoker(
             ExternalInvoker::from_executable(tool_exe),
             Some(toolchain_dir),
+            None,
         )
     }
 
     pub fn from_executable(executable: &Path) -> Self {
-        Self::from_external_invoker(ExternalInvoker::from_executable(executable), None)
+        Self::from_external_invoker(ExternalInvoker::from_executable(executable), None, None)
+    }
+
+    /// Create the invoker from the toolchain handed off by a parent invocation.
+    ///
+    /// Returns `None` if there is no hand-off or it was made for a different resolution.
+    pub fn from_parent_resolution(invocation: &Invocation, resolution_key: &str) -> Option<Self> {
+        let toolchain_dir = std::env::var_os("NIX_RUST_WRANGLER_RESOLVED_TOOLCHAIN")?;
+        let parent_key = std::env::var("NIX_RUST_WRANGLER_RESOLUTION_KEY").ok()?;
+
+        if parent_key != resolution_key {
+            tracing::debug!("Resolution of parent does not match, resolving again");
+            return None;
+        }
+
+        Self::from_toolchain_dir(Path::new(&toolchain_dir), invocation, Some(resolution_key))
+            .map_err(|err| tracing::debug!("Unable to use toolchain of parent: {}", err))
+            .ok()
     }
 
-    fn from_external_invoker(mut invoker: ExternalInvoker, toolchain_dir: Option<&Path>) -> Self {
+    fn from_external_invoker(
+        mut invoker: ExternalInvoker,
+        toolchain_dir: Option<&Path>,
+        resolution_key: Option<&str>,
+    ) -> Self {
         invoker.configure_command(|cmd| {
             Self::configure_command_for_toolchain(toolchain_dir, cmd);
             cmd.env("RUST_RECURSION_COUNT", "0");
+
+            match (toolchain_dir, resolution_key) {
+                (Some(toolchain_dir), Some(resolution_key)) => {
+                    cmd.env("NIX_RUST_WRANGLER_RESOLVED_TOOLCHAIN", toolchain_dir);
+                    cmd.env("NIX_RUST_WRANGLER_RESOLUTION_KEY", resolution_key);
+                }
+                _ => {
+                    cmd.env_remove("NIX_RUST_WRANGLER_RESOLVED_TOOLCHAIN");
+                    cmd.env_remove("NIX_RUST_WRANGLER_RESOLUTION_KEY");
+                }
+            }
         });
         Self::new(Inner::External(invoker))
     }
@@ -113,6 +154,21 @@ impl ToolInvoker {
         )))
     }
 
+    /// Create the invoker from a command which already passes the invocation arguments.
+    pub fn from_complete_command(command: Command) -> Self {
+        Self::new(Inner::ExternalWithArgs(ExternalInvoker::from_command(
+            command,
+        )))
+    }
+
+    /// Modify the environment of the command the tool is started with.
+    pub fn configure_env(&mut self, configure: impl FnOnce(&mut Command)) {
+        match &mut self.inner {
+            Inner::Internal => {}
+            Inner::External(v) | Inner::ExternalWithArgs(v) => v.configure_command(configure),
+        }
+    }
+
     fn new(inner: Inner) -> Self {
         Self {
             inner,
@@ -125,21 +181,22 @@ impl ToolInvoker {
     }
 
     pub fn dispatch(self, args: &[OsString]) {
-        match self.inner {
+        let (mut v, args) = match self.inner {
             Inner::Internal => todo!(),
-            Inner::External(mut v) => {
-                v.configure_command(|cmd| {
-                    cmd.args(args);
-                    if let Some(flake) = &self.used_flake {
-                        cmd.env("NIX_RUST_WRANGLER_FLAKE_PATH", flake.path());
-                    }
-                });
-
-                tracing::trace!("Executing external invoker: {:?}", v);
-                let err = v.exec();
-                tracing::error!("Failed to execute command: {}", err);
-                std::process::exit(1);
+            Inner::External(v) => (v, args),
+            Inner::ExternalWithArgs(v) => (v, &[][..]),
+        };
+
+        v.configure_command(|cmd| {
+            cmd.args(args);
+            if let Some(flake) = &self.used_flake {
+                cmd.env("NIX_RUST_WRANGLER_FLAKE_PATH", flake.path());
             }
-        }
+        });
+
+        tracing::trace!("Executing external invoker: {:?}", v);
+        let err = v.exec();
+        tracing::error!("Failed to execute command: {}", err);
+        std::process::exit(1);
     }
 }
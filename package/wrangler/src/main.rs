@@ -1,22 +1,30 @@
+mod cache;
 mod collection;
+mod daemon;
 pub mod error;
 mod invocation;
 mod invoker;
 mod nix;
 mod util;
 
+use crate::cache::command::run_cache_command;
+use crate::cache::FlakeCache;
 use crate::collection::ToolchainCollection;
+use crate::daemon::server::run_daemon;
+use crate::error::{CollectionError, Error, FlakeEvalError};
 use crate::invocation::{Invocation, InvokedTool};
 use crate::invoker::ToolInvoker;
-use crate::nix::config::FlakeInspection;
+use crate::nix::config::{FlakeInspection, Passthrough};
+use crate::nix::evaluator::{run_revalidation, FlakeEvaluator};
 use crate::nix::flake::NixFlake;
+use crate::nix::project::NixProject;
 use crate::nix::proxy::run_develop_proxy;
 use crate::nix::NixCommand;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
 fn main() {
-    let invocation = match Invocation::derive() {
+    let mut invocation = match Invocation::derive() {
         Ok(v) => v,
         Err(err) => {
             tracing::error!("{}", err);
@@ -47,6 +55,15 @@ fn main() {
         InvokedTool::NixDevelopProxy => {
             run_develop_proxy(invocation);
         }
+        InvokedTool::Cache => {
+            run_cache_command(invocation);
+        }
+        InvokedTool::Daemon => {
+            run_daemon(find_nix());
+        }
+        InvokedTool::NixRevalidateCache => {
+            run_revalidation(find_nix);
+        }
         _ => { /* fall through */ }
     }
 
@@ -54,29 +71,99 @@ fn main() {
         tracing::warn!("Unknown tool invocation: {}", name.to_string_lossy());
     }
 
-    if let Some((nix_command, flake)) =
-        find_nix().and_then(|cmd| NixFlake::find_automatically().map(|f| (cmd, f)))
-    {
+    if let Some(invoker) = invoker_from_parent_resolution(&invocation) {
+        tracing::debug!("Using toolchain resolved by parent invocation");
+        dispatch(invoker, &invocation);
+    }
+
+    let project = find_nix().and_then(|cmd| NixProject::find_automatically().map(|p| (cmd, p)));
+
+    if let Some((nix_command, NixProject::Shell(shell))) = &project {
+        tracing::info!("Using nix shell at {}", shell.path().display());
+
+        match shell.make_invoker(nix_command, &invocation) {
+            Ok(invoker) => dispatch(invoker, &invocation),
+            Err(err) => {
+                tracing::error!("Failed to create tool invoker: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Set if nix timed out and falling back to toolchains outside of the flake is allowed
+    let mut flake_timed_out = false;
+
+    // Alias the toolchain override has been resolved from
+    let mut toolchain_alias = None;
+
+    if let Some((nix_command, NixProject::Flake(flake))) = project {
         tracing::info!("Using flake at {}", flake.path().display());
 
-        let evaluation = match flake.apply_expr_json::<FlakeInspection>(
-            &nix_command,
-            ".",
-            FlakeInspection::APPLY_EXPR,
-        ) {
-            Ok(v) => v,
+        let evaluator = FlakeEvaluator::new(&nix_command, &flake);
+        let evaluation = match evaluator.inspect() {
+            Ok(v) => Some(v),
+            Err(err) if allows_timeout_fallback(&err) => {
+                tracing::warn!("{}, continuing search outside of the flake", err);
+                flake_timed_out = true;
+                None
+            }
             Err(err) => {
+                dispatch_last_known_good(&evaluator, &invocation, None, &err);
                 tracing::error!("Failed to evaluate flake: {}", err);
                 std::process::exit(1);
             }
         };
 
-        tracing::trace!("Flake evaluation: {:#?}", evaluation);
+        // Nothing may use an invalid configuration, including aliases and passthrough
+        if let Some(Err(err)) = evaluation.as_ref().map(FlakeInspection::validate) {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
 
-        let invoker = match evaluation.make_invoker(&nix_command, &flake, &invocation) {
+        // Aliases apply to the toolchains of the flake as well as to the collection
+        match evaluation
+            .as_ref()
+            .map(|v| v.resolve_toolchain_alias(&invocation.toolchain_override))
+        {
+            Some(Ok(Some(resolved))) => {
+                tracing::debug!(
+                    "Toolchain alias {:?} resolves to {:?}",
+                    invocation.toolchain_override,
+                    resolved
+                );
+                toolchain_alias = invocation
+                    .toolchain_override
+                    .as_override_name()
+                    .map(String::from);
+                invocation.toolchain_override = resolved;
+            }
+            Some(Err(err)) => {
+                tracing::error!("Failed to resolve toolchain alias: {}", err);
+                std::process::exit(1);
+            }
+            Some(Ok(None)) | None => {}
+        }
+
+        // Tools passed through to the collection continue the search outside of the flake
+        let passthrough = evaluation.as_ref().and_then(|v| v.passthrough(&invocation));
+        if passthrough == Some(Passthrough::System) {
+            dispatch_system_passthrough(&invocation);
+        }
+        let evaluation = evaluation.filter(|_| passthrough.is_none());
+
+        let invoker = match evaluation.as_ref().and_then(|evaluation| {
+            tracing::trace!("Flake evaluation: {:#?}", evaluation);
+            evaluation.make_invoker(&evaluator, &invocation)
+        }) {
             None => None,
             Some(Ok(v)) => Some(v),
+            Some(Err(Error::Flake(err))) if allows_timeout_fallback(&err) => {
+                tracing::warn!("{}, continuing search outside of the flake", err);
+                flake_timed_out = true;
+                None
+            }
             Some(Err(err)) => {
+                dispatch_last_known_good(&evaluator, &invocation, evaluation.as_ref(), &err);
                 tracing::error!("Failed to create tool invoker: {}", err);
                 std::process::exit(1);
             }
@@ -91,13 +178,17 @@ fn main() {
     let toolchain_collection = match ToolchainCollection::find() {
         None => {
             // Last resort: attempt to invoke tool from system path
-            if util::was_dispatched_into_flake() {
+            if util::was_dispatched_into_flake() || flake_timed_out {
                 if let Some(invoker) = invoker_for_system_path(&invocation) {
                     dispatch(invoker, &invocation);
                     return;
                 }
             }
 
+            if let Some(alias) = toolchain_alias {
+                exit_with_unknown_alias_target(alias, &invocation);
+            }
+
             tracing::error!("No toolchain found in flake and no tool found in system path");
             std::process::exit(1);
         }
@@ -110,6 +201,11 @@ fn main() {
         invocation.tool == InvokedTool::Cargo,
     ) {
         Ok(v) => v,
+        Err(Error::Collection(CollectionError::ToolchainNotFound(_)))
+            if toolchain_alias.is_some() =>
+        {
+            exit_with_unknown_alias_target(toolchain_alias.unwrap_or_default(), &invocation);
+        }
         Err(err) => {
             tracing::error!("{}", err);
             std::process::exit(1);
@@ -120,33 +216,97 @@ fn main() {
     dispatch(invoker, &invocation);
 }
 
+fn exit_with_unknown_alias_target(alias: String, invocation: &Invocation) -> ! {
+    let err = Error::UnknownAliasTarget {
+        alias,
+        target: invocation
+            .toolchain_override
+            .as_override_name()
+            .unwrap_or_default()
+            .to_string(),
+    };
+
+    tracing::error!("{}", err);
+    std::process::exit(1);
+}
+
+/// In offline mode, fall back to the toolchain resolved the last time nix succeeded.
+fn dispatch_last_known_good(
+    evaluator: &FlakeEvaluator,
+    invocation: &Invocation,
+    evaluation: Option<&FlakeInspection>,
+    err: &dyn std::fmt::Display,
+) {
+    if let Some(mut invoker) = evaluator.last_known_good_invoker(invocation, evaluation, err) {
+        invoker.set_flake(evaluator.flake().clone());
+        dispatch(invoker, invocation);
+    }
+}
+
+/// Whether a flake error is a timeout, after which NIX_RUST_WRANGLER_TIMEOUT_FALLBACK allows
+/// using toolchains outside of the flake.
+fn allows_timeout_fallback(err: &FlakeEvalError) -> bool {
+    matches!(err, FlakeEvalError::Timeout { .. })
+        && util::flag_from_env("NIX_RUST_WRANGLER_TIMEOUT_FALLBACK")
+}
+
 fn find_nix() -> Option<NixCommand> {
     if util::was_dispatched_into_flake() {
         tracing::debug!("Already dispatched into flake, skipping nix command search to prevent infinite recursion");
         return None;
     }
 
-    let nix_command = if std::env::var_os("NIX_RUST_WRANGLER_DISABLE_NIX")
-        .map(|v| v.len() > 0)
-        .unwrap_or(false)
-    {
+    if util::flag_from_env("NIX_RUST_WRANGLER_DISABLE_NIX") {
         tracing::info!("Nix command disabled by NIX_RUST_WRANGLER_DISABLE_NIX");
-        None
-    } else {
-        NixCommand::find()
-    };
+        return None;
+    }
 
-    match nix_command.as_ref() {
-        None => tracing::info!("No nix command found, flake support will be disabled"),
-        Some(v) if v.is_usable() && v.flakes_enabled() => {
-            tracing::info!("Nix is available with flakes enabled");
-        }
-        Some(_) => tracing::info!("Nix found, but it is not enabled or flakes are not enabled"),
+    // Nix is probed once it is needed, which it isn't if the daemon answers
+    let nix_command = NixCommand::find();
+    if nix_command.is_none() {
+        tracing::info!("No nix command found, flake support will be disabled");
     }
 
     nix_command
 }
 
+/// Nested invocations (for example rustc spawned by cargo) can re-use the toolchain the
+/// parent resolved, as long as the flake and toolchain override are unchanged.
+fn invoker_from_parent_resolution(invocation: &Invocation) -> Option<ToolInvoker> {
+    std::env::var_os("NIX_RUST_WRANGLER_RESOLUTION_KEY")?;
+
+    let flake = NixFlake::find_automatically()?;
+    let resolution_key = FlakeEvaluator::resolution_key(
+        &FlakeCache::current_key(&flake),
+        invocation.toolchain_name(),
+    );
+
+    let mut invoker = ToolInvoker::from_parent_resolution(invocation, &resolution_key)?;
+    invoker.set_flake(flake);
+    Some(invoker)
+}
+
+/// Invoke a tool the flake passes through to the system PATH.
+fn dispatch_system_passthrough(invocation: &Invocation) -> ! {
+    let Some(path_delegate) =
+        util::find_foreign_executable_in_path(invocation.tool.to_executable_name())
+    else {
+        tracing::error!(
+            "The flake passes {} through to the system path, but it was not found there",
+            invocation.tool.to_name()
+        );
+        std::process::exit(1);
+    };
+
+    tracing::debug!(
+        "Passing {} through to system path: {}",
+        invocation.tool.to_name(),
+        path_delegate.display()
+    );
+    dispatch(ToolInvoker::from_executable(&path_delegate), invocation);
+    std::process::exit(1);
+}
+
 fn invoker_for_system_path(invocation: &Invocation) -> Option<ToolInvoker> {
     let path_delegate = util::find_executable_in_path(invocation.tool.to_executable_name())?;
     tracing::debug!(
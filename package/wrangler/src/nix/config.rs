@@ -1,14 +1,16 @@
 use crate::error::{Error, FlakeEvalError};
 use crate::invocation::{Invocation, InvokedTool, ToolchainOverride};
 use crate::invoker::ToolInvoker;
-use crate::nix::flake::NixFlake;
-use crate::nix::NixCommand;
+use crate::nix::env::{BuildInput, FlakeEnv};
+use crate::nix::evaluator::FlakeEvaluator;
+use crate::nix::flake::{BuildTarget, NixFlake};
+use crate::nix::proxy::configure_proxy_command;
+use crate::nix::{nix_attr_name, NixCommand};
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fmt::Display;
+use std::fmt::{Display, Formatter};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -18,6 +20,10 @@ pub struct FlakeInspection {
     pub default_dev_shell: Option<FlakeValueType>,
     pub rust_wrangler_dev_shell: Option<FlakeValueType>,
 
+    /// All dev shells of the flake, by name.
+    #[serde(default)]
+    pub dev_shells: HashMap<String, FlakeValueType>,
+
     // More complex config
     pub config: Option<FlakeEmbeddedConfigAttr>,
 }
@@ -25,13 +31,89 @@ pub struct FlakeInspection {
 impl FlakeInspection {
     pub const APPLY_EXPR: &'static str = include_str!("./inspect-flake.nix");
 
+    /// Build the expression applied to the flake outputs, inspecting for the given system.
+    pub fn apply_expr(system: &str) -> String {
+        format!(
+            "({}) {}",
+            Self::APPLY_EXPR,
+            crate::nix::nix_string_literal(system)
+        )
+    }
+
+    /// Validate the configuration, unless the flake is ignored anyway.
+    pub fn validate(&self) -> Result<(), Error> {
+        match &self.config {
+            Some(config) if !config.ignore => config.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Extra nix arguments requested by the flake configuration.
+    pub fn nix_args(&self) -> &[String] {
+        self.config
+            .as_ref()
+            .map(|config| config.nix_args.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Resolve the toolchain override through the aliases of the configuration.
+    ///
+    /// Returns `None` if the override is not an alias.
+    pub fn resolve_toolchain_alias(
+        &self,
+        toolchain_override: &ToolchainOverride,
+    ) -> Result<Option<ToolchainOverride>, Error> {
+        let (Some(config), Some(name)) = (&self.config, toolchain_override.as_override_name())
+        else {
+            return Ok(None);
+        };
+
+        Ok(config
+            .resolve_alias(name)?
+            .map(|target| toolchain_override.with_name(target)))
+    }
+
+    /// Name of the toolchain the configuration selects for the invocation, which the last
+    /// known good toolchains are stored under. The default toolchain uses an empty name.
+    pub fn selected_toolchain_name<'a>(
+        &'a self,
+        flake_dir: &Path,
+        invocation: &'a Invocation,
+    ) -> &'a str {
+        let selected = self.config.as_ref().and_then(|config| {
+            config
+                .select_toolchain(flake_dir, invocation)
+                .map(|selection| selection.name())
+        });
+
+        invocation
+            .toolchain_override
+            .as_override_name()
+            .or(selected)
+            .unwrap_or_default()
+    }
+
+    /// Where to search for the tool instead of the flake, if it is configured to bypass it.
+    pub fn passthrough(&self, invocation: &Invocation) -> Option<Passthrough> {
+        let config = self.config.as_ref()?;
+        let tool_name = invocation.tool.to_name();
+
+        config.passthrough.get(&tool_name).copied().or_else(|| {
+            config
+                .ignore_tools
+                .contains(&tool_name)
+                .then_some(Passthrough::Collection)
+        })
+    }
+
     /// Attempt to create an invoker which invokes the tool using the flake's configuration.
     pub fn make_invoker(
         &self,
-        nix_command: &NixCommand,
-        flake: &NixFlake,
+        evaluator: &FlakeEvaluator,
         invocation: &Invocation,
     ) -> Option<Result<ToolInvoker, Error>> {
+        let nix_command = &evaluator.flake_nix_command();
+        let flake = evaluator.flake();
         let mut toolchain_for_cargo_fallback = None;
 
         if let Some(config) = &self.config {
@@ -39,19 +121,54 @@ impl FlakeInspection {
                 return None;
             }
 
-            if let Some(name) = invocation.toolchain_override.as_override_name() {
+            let tool_name = invocation.tool.to_name();
+            match config.tools.get(&tool_name) {
+                Some(FlakeValueType::Derivation) => {
+                    return Some(self.build_tool(invocation, evaluator, config, &tool_name));
+                }
+                Some(_) => {
+                    tracing::warn!("Tool attribute {} is not a derivation, ignoring", tool_name)
+                }
+                None => {}
+            }
+
+            let selection = config.select_toolchain(flake.dir(), invocation);
+
+            // Nested invocations may run in other directories, so the toolchain selected for
+            // the directory is passed on to them
+            let directory_invocation = match selection {
+                Some(ToolchainSelection::Directory(name)) => Some(Invocation {
+                    directory_toolchain: Some(name.to_string()),
+                    ..invocation.clone()
+                }),
+                _ => None,
+            };
+            let invocation = directory_invocation.as_ref().unwrap_or(invocation);
+
+            if let Some(name) = invocation
+                .toolchain_override
+                .as_override_name()
+                .or(selection.map(|selection| selection.name()))
+            {
                 if let Some(toolchain) = config.toolchains.get(name) {
-                    if let FlakeValueType::Derivation = toolchain {
+                    if self.is_usable_toolchain(&format!("toolchains.{}", name), toolchain) {
                         let build_result = self.build_toolchain(
                             invocation,
-                            nix_command,
-                            flake,
+                            evaluator,
                             config,
-                            format!("toolchains.{}", name),
+                            Some(name),
+                            toolchain,
                         );
 
                         match build_result {
-                            Ok(v) => return Some(Ok(v)),
+                            Ok(mut v) => {
+                                if directory_invocation.is_some() {
+                                    v.configure_env(|cmd| {
+                                        cmd.env("NIX_RUST_WRANGLER_DIRECTORY_TOOLCHAIN", name);
+                                    });
+                                }
+                                return Some(Ok(v));
+                            }
                             Err(Error::ToolchainDoesNotProvideTool { path, .. })
                                 if invocation.tool == InvokedTool::Cargo =>
                             {
@@ -64,11 +181,6 @@ impl FlakeInspection {
                             }
                             Err(err) => return Some(Err(err)),
                         }
-                    } else {
-                        tracing::warn!(
-                            "Toolchain attribute {} is not a derivation, ignoring",
-                            name
-                        );
                     }
                 }
 
@@ -85,19 +197,12 @@ impl FlakeInspection {
             let toolchain_build_result = config
                 .toolchain
                 .as_ref()
-                .filter(|v| {
-                    if let FlakeValueType::Derivation = **v {
-                        true
-                    } else {
-                        tracing::warn!("Toolchain attribute is not a derivation, ignoring");
-                        false
-                    }
-                })
-                .map(|_| self.build_toolchain(invocation, nix_command, flake, config, "toolchain"));
+                .filter(|v| self.is_usable_toolchain("toolchain", v))
+                .map(|v| self.build_toolchain(invocation, evaluator, config, None, v));
 
             match toolchain_build_result {
                 None => {
-                    if !config.toolchains.is_empty() {
+                    if !config.toolchains.is_empty() && config.dev_shell.is_none() {
                         tracing::warn!(
                             "No default toolchain found, but overrides are defined. \
                             Did you mean to define a default toolchain? \
@@ -118,6 +223,32 @@ impl FlakeInspection {
             }
         }
 
+        // A dev shell selected by name replaces the default ones
+        let selected_dev_shell = std::env::var("NIX_RUST_WRANGLER_DEV_SHELL")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .or_else(|| self.config.as_ref().and_then(|v| v.dev_shell.clone()));
+
+        if let Some(shell_name) = selected_dev_shell {
+            if self.has_dev_shell(&shell_name) {
+                return Some(
+                    self.create_develop_proxy(
+                        nix_command,
+                        flake,
+                        Some(&shell_name),
+                        &invocation.toolchain_override,
+                        toolchain_for_cargo_fallback,
+                    )
+                    .map(|cmd| ToolInvoker::from_command_proxy(cmd, invocation)),
+                );
+            }
+
+            tracing::warn!(
+                "Selected dev shell '{}' does not exist or is not a derivation, ignoring",
+                shell_name
+            );
+        }
+
         // No special toolchain config, attempt to use dev shells
         self.rust_wrangler_dev_shell
             .as_ref()
@@ -148,6 +279,40 @@ impl FlakeInspection {
             .map(|cmd| cmd.map(|cmd| ToolInvoker::from_command_proxy(cmd, invocation)))
     }
 
+    fn has_dev_shell(&self, name: &str) -> bool {
+        self.dev_shells.get(name) == Some(&FlakeValueType::Derivation)
+    }
+
+    /// Check whether a toolchain can be used, warning about it otherwise.
+    fn is_usable_toolchain(&self, attr: &str, toolchain: &ToolchainEntry) -> bool {
+        match toolchain {
+            ToolchainEntry::DevShell { dev_shell } => {
+                let exists = self.has_dev_shell(dev_shell);
+                if !exists {
+                    tracing::warn!(
+                        "Toolchain attribute {} refers to dev shell '{}', which does not exist or is not a derivation, ignoring",
+                        attr,
+                        dev_shell
+                    );
+                }
+                exists
+            }
+            _ => {
+                let is_buildable = matches!(
+                    toolchain.value_type(),
+                    Some(FlakeValueType::Derivation | FlakeValueType::ToolchainSpec)
+                );
+                if !is_buildable {
+                    tracing::warn!(
+                        "Toolchain attribute {} is neither a derivation nor a toolchain spec, ignoring",
+                        attr
+                    );
+                }
+                is_buildable
+            }
+        }
+    }
+
     /// Create a command which re-executes ourselves in a nix-develop shell.
     fn create_develop_proxy(
         &self,
@@ -160,65 +325,148 @@ impl FlakeInspection {
         let own_executable =
             std::env::current_exe().map_err(Error::UnableToDetermineOwnExecutable)?;
 
-        let mut cmd = nix_command.new_command();
-        cmd.arg("develop");
-
-        if let Some(shell_name) = shell_name {
-            let mut path = OsString::from(flake.dir());
-            path.push("#");
-            path.push(shell_name.as_ref());
+        let mut cmd = nix_command.new_command("develop");
 
-            cmd.arg(path);
-        } else {
-            cmd.arg(flake.dir());
-        }
+        cmd.arg(flake.dev_shell_installable(shell_name.as_ref().map(|v| v.as_ref())));
 
         cmd.arg("--command");
         cmd.arg(own_executable);
         cmd.arg("nix-develop-proxy");
 
-        if let Some(toolchain_for_cargo_fallback) = toolchain_for_cargo_fallback {
-            cmd.env(
-                "NIX_RUST_WRANGLER_TOOLCHAIN_FALLBACK",
-                toolchain_for_cargo_fallback,
-            );
-        }
-        
-        match toolchain_override {
-            ToolchainOverride::FromArg(name) | ToolchainOverride::FromEnv(name) => {
-                cmd.env("RUSTUP_TOOLCHAIN", name);
-                cmd.env("NIX_RUST_WRANGLER_TOOLCHAIN", name);
-            }
-            ToolchainOverride::None => {}
-        }
-
-        cmd.env("NIX_RUST_WRANGLER_INSIDE_NIX_DEVELOP", "1");
+        configure_proxy_command(&mut cmd, toolchain_override, toolchain_for_cargo_fallback);
 
         Ok(cmd)
     }
 
+    /// Build a toolchain, either one of the named toolchains or the default one.
     fn build_toolchain(
         &self,
         invocation: &Invocation,
-        nix_command: &NixCommand,
-        flake: &NixFlake,
+        evaluator: &FlakeEvaluator,
         config: &FlakeEmbeddedConfigAttr,
-        toolchain_attr_path: impl Display,
+        toolchain_name: Option<&str>,
+        toolchain: &ToolchainEntry,
     ) -> Result<ToolInvoker, Error> {
-        let build_result = flake.build(
-            nix_command,
-            format!("{}.{}", config.at, toolchain_attr_path),
-        )?;
+        if let ToolchainEntry::DevShell { dev_shell } = toolchain {
+            return self
+                .create_develop_proxy(
+                    &evaluator.flake_nix_command(),
+                    evaluator.flake(),
+                    Some(dev_shell),
+                    &invocation.toolchain_override,
+                    None,
+                )
+                .map(|cmd| ToolInvoker::from_command_proxy(cmd, invocation));
+        }
+
+        let toolchain_attr_path = match toolchain_name {
+            Some(name) => format!("toolchains.{}", nix_attr_name(name)),
+            None => "toolchain".to_string(),
+        };
+        let attr = format!(
+            "{}.{}{}",
+            config.at,
+            toolchain_attr_path,
+            toolchain.package_attr_suffix()
+        );
+
+        // Toolchain specs are turned into derivations by the builder the flake provides
+        let target = match toolchain.value_type() {
+            Some(FlakeValueType::ToolchainSpec) => BuildTarget::Applied {
+                builder: format!("{}.mkToolchain", config.at),
+                argument: attr,
+            },
+            _ => BuildTarget::Attr(attr),
+        };
+        let build_result = evaluator.build(&target)?;
 
         // Find the first usable built toolchain derivation
         for output in build_result {
             if let Some(path) = output.outputs.get("out") {
-                return ToolInvoker::from_toolchain_dir(path, invocation);
+                let resolution_key = config.allows_hand_off(invocation).then(|| {
+                    FlakeEvaluator::resolution_key(evaluator.key(), invocation.toolchain_name())
+                });
+                let mut invoker =
+                    ToolInvoker::from_toolchain_dir(path, invocation, resolution_key.as_deref())?;
+
+                // Variables of the toolchain take precedence over the global ones
+                Self::apply_build_inputs(evaluator, config, &mut invoker)?;
+                for env in std::iter::once(&config.env).chain(toolchain.env()) {
+                    Self::apply_env(evaluator, env, &mut invoker)?;
+                }
+
+                evaluator.store_last_good(toolchain_name.unwrap_or_default(), path);
+                return Ok(invoker);
             }
         }
 
         Err(Error::Flake(FlakeEvalError::MissingToolchainDerivation))
     }
+
+    /// Build the derivation configured for a single tool and invoke the tool from it.
+    fn build_tool(
+        &self,
+        invocation: &Invocation,
+        evaluator: &FlakeEvaluator,
+        config: &FlakeEmbeddedConfigAttr,
+        tool_name: &str,
+    ) -> Result<ToolInvoker, Error> {
+        let build_result = evaluator.build(&BuildTarget::Attr(format!(
+            "{}.tools.{}",
+            config.at,
+            nix_attr_name(tool_name)
+        )))?;
+
+        let path = build_result
+            .iter()
+            .find_map(|output| output.outputs.get("out"))
+            .ok_or(FlakeEvalError::MissingToolchainDerivation)?;
+
+        // Nested invocations of other tools have to resolve their toolchain themselves
+        let mut invoker = ToolInvoker::from_toolchain_dir(path, invocation, None)?;
+        Self::apply_build_inputs(evaluator, config, &mut invoker)?;
+        Self::apply_env(evaluator, &config.env, &mut invoker)?;
+
+        Ok(invoker)
+    }
+
+    /// Make the build inputs available to the tool, building them first if necessary.
+    fn apply_build_inputs(
+        evaluator: &FlakeEvaluator,
+        config: &FlakeEmbeddedConfig,
+        invoker: &mut ToolInvoker,
+    ) -> Result<(), Error> {
+        if config.build_inputs.is_empty() {
+            return Ok(());
+        }
+
+        evaluator.realise(&FlakeEnv::unrealised_build_inputs(&config.build_inputs))?;
+
+        let env = FlakeEnv::from_build_inputs(&config.build_inputs);
+        invoker.configure_env(|cmd| env.apply(cmd));
+        Ok(())
+    }
+
+    /// Apply declared environment variables to an invoker, building the derivations they
+    /// refer to first if necessary.
+    fn apply_env(
+        evaluator: &FlakeEvaluator,
+        env: &FlakeEnv,
+        invoker: &mut ToolInvoker,
+    ) -> Result<(), Error> {
+        if env.is_empty() {
+            return Ok(());
+        }
+
+        let missing = env.missing_store_paths();
+        if !missing.is_empty() {
+            tracing::debug!("Building store paths used by environment: {:?}", missing);
+            evaluator.realise(&env.requires)?;
+        }
+
+        invoker.configure_env(|cmd| env.apply(cmd));
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -240,15 +488,293 @@ pub struct FlakeEmbeddedConfig {
     #[serde(default)]
     pub ignore: bool,
 
-    pub toolchain: Option<FlakeValueType>,
+    /// Whether issues found by validating the configuration are errors instead of warnings.
+    #[serde(default = "strict_by_default")]
+    pub strict: bool,
 
+    /// Problems found by validating the configuration during the inspection.
     #[serde(default)]
-    pub toolchains: HashMap<String, FlakeValueType>,
+    pub issues: Vec<ConfigIssue>,
+
+    pub toolchain: Option<ToolchainEntry>,
+
+    #[serde(default)]
+    pub toolchains: HashMap<String, ToolchainEntry>,
+
+    /// Extra arguments passed to nix when building toolchains or entering dev shells.
+    ///
+    /// The inspection only accepts a few arguments, see `allowedNixArgs`, and drops the
+    /// list entirely if it contains others.
+    #[serde(default, rename = "nixArgs")]
+    pub nix_args: Vec<String>,
+
+    /// Derivations providing single tools instead of the toolchain, by tool name.
+    #[serde(default)]
+    pub tools: HashMap<String, FlakeValueType>,
+
+    /// Names of the toolchains to use for single tools, by tool name.
+    #[serde(default, rename = "toolToolchain")]
+    pub tool_toolchain: HashMap<String, String>,
+
+    /// Environment variables set for all tools started from the flake's configuration.
+    #[serde(default)]
+    pub env: FlakeEnv,
+
+    /// Derivations whose binaries, libraries and headers are made available to tools.
+    #[serde(default, rename = "buildInputs")]
+    pub build_inputs: Vec<BuildInput>,
+
+    /// Alternative names of toolchains, which are either defined by the flake or
+    /// installed in the collection.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+
+    /// Name of the dev shell to use instead of the default ones.
+    #[serde(default, rename = "devShell")]
+    pub dev_shell: Option<String>,
+
+    /// Names of the toolchains to use for directories relative to the flake directory.
+    #[serde(default)]
+    pub directories: HashMap<String, String>,
+
+    /// Tools which don't use the flake, but continue the search outside of it.
+    #[serde(default, rename = "ignoreTools")]
+    pub ignore_tools: Vec<String>,
+
+    /// Where to search for tools which don't use the flake, by tool name.
+    #[serde(default)]
+    pub passthrough: HashMap<String, Passthrough>,
+}
+
+fn strict_by_default() -> bool {
+    true
+}
+
+impl FlakeEmbeddedConfig {
+    /// Report the issues found by validating the configuration, which fails in strict mode.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.issues.is_empty() {
+            return Ok(());
+        }
+
+        if self.strict {
+            return Err(Error::InvalidConfig {
+                issues: self.issues.iter().map(ToString::to_string).collect(),
+            });
+        }
+
+        for issue in &self.issues {
+            tracing::warn!("Invalid flake configuration: {}", issue);
+        }
+        Ok(())
+    }
+
+    /// Select the toolchain for an invocation without an explicit override, using
+    /// `toolToolchain` or, as the least specific selection, `directories`. The directory
+    /// selection of a parent invocation takes the place of the one for the directory.
+    ///
+    /// Selections of toolchains which don't exist are ignored with a warning.
+    pub fn select_toolchain(
+        &self,
+        flake_dir: &Path,
+        invocation: &Invocation,
+    ) -> Option<ToolchainSelection<'_>> {
+        if invocation.toolchain_override.as_override_name().is_some() {
+            return None;
+        }
+
+        let tool_name = invocation.tool.to_name();
+        let tool_toolchain = self.tool_toolchain.get(&tool_name).filter(|name| {
+            let exists = self.toolchains.contains_key(*name);
+            if !exists {
+                tracing::warn!(
+                    "toolToolchain selects unknown toolchain '{}' for {}, using the default toolchain",
+                    name,
+                    tool_name
+                );
+            }
+            exists
+        });
+
+        if let Some(name) = tool_toolchain {
+            return Some(ToolchainSelection::Tool(name));
+        }
+
+        let inherited = invocation
+            .directory_toolchain
+            .as_deref()
+            .and_then(|name| self.toolchains.get_key_value(name));
+        if let Some((name, _)) = inherited {
+            return Some(ToolchainSelection::Directory(name));
+        }
+
+        let dir = invocation.target_dir()?;
+        self.directory_toolchain(flake_dir, &dir)
+            .filter(|name| {
+                let exists = self.toolchains.contains_key(*name);
+                if !exists {
+                    tracing::warn!(
+                        "directories selects unknown toolchain '{}' for {}, using the default toolchain",
+                        name,
+                        dir.display()
+                    );
+                }
+                exists
+            })
+            .map(ToolchainSelection::Directory)
+    }
+
+    /// Whether the toolchain resolved for an invocation may be handed off to nested
+    /// invocations of other tools.
+    ///
+    /// This is not the case if tools can resolve differently or bypass the flake, as
+    /// nested invocations skip evaluating the configuration. Nested invocations may also
+    /// operate on directories which select another toolchain, unless the toolchain is
+    /// already selected by an override or for the directory of the invocation, which is
+    /// passed on to them.
+    pub fn allows_hand_off(&self, invocation: &Invocation) -> bool {
+        self.tools.is_empty()
+            && self.tool_toolchain.is_empty()
+            && self.ignore_tools.is_empty()
+            && self.passthrough.is_empty()
+            && (self.directories.is_empty() || invocation.toolchain_name().is_some())
+    }
+
+    /// The toolchain configured for the longest prefix of the directory in `directories`.
+    pub fn directory_toolchain(&self, flake_dir: &Path, dir: &Path) -> Option<&str> {
+        let flake_dir = flake_dir
+            .canonicalize()
+            .unwrap_or_else(|_| flake_dir.into());
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.into());
+        let relative_dir = dir.strip_prefix(&flake_dir).ok()?;
+
+        self.directories
+            .iter()
+            .map(|(prefix, name)| {
+                let prefix = Path::new(prefix)
+                    .components()
+                    .filter(|c| *c != Component::CurDir)
+                    .collect::<PathBuf>();
+                (prefix, name)
+            })
+            .filter(|(prefix, _)| relative_dir.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// Follow the aliases starting at a toolchain name, returning the toolchain name it
+    /// finally refers to or `None` if the name is not an alias.
+    pub fn resolve_alias<'a>(&'a self, name: &'a str) -> Result<Option<&'a str>, Error> {
+        let mut chain = vec![name];
+
+        while let Some(target) = self.aliases.get(chain[chain.len() - 1]) {
+            let is_cycle = chain.contains(&target.as_str());
+            chain.push(target);
+
+            if is_cycle {
+                return Err(Error::AliasCycle {
+                    chain: chain.into_iter().map(String::from).collect(),
+                });
+            }
+        }
+
+        Ok(chain.last().copied().filter(|_| chain.len() > 1))
+    }
+}
+
+/// How the toolchain of an invocation without an explicit override has been selected.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ToolchainSelection<'a> {
+    /// By `toolToolchain` for the invoked tool.
+    Tool(&'a str),
+
+    /// By `directories` for the directory the tool operates on.
+    Directory(&'a str),
+}
+
+impl<'a> ToolchainSelection<'a> {
+    pub fn name(&self) -> &'a str {
+        match self {
+            Self::Tool(name) | Self::Directory(name) => name,
+        }
+    }
+}
+
+/// Where a tool is searched for instead of the flake.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Passthrough {
+    /// The system PATH, skipping the wrangler itself.
+    System,
+
+    /// The toolchain collection, like tools which are not configured by the flake.
+    Collection,
+}
+
+/// A problem with the configuration, at the attribute path of the flake outputs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// A toolchain, either given as a derivation, as `{ package = drv; env = { ... }; }` or as
+/// `{ devShell = "name"; }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ToolchainEntry {
+    Package(FlakeValueType),
+    WithEnv {
+        #[serde(rename = "type")]
+        value_type: FlakeValueType,
+        #[serde(default)]
+        env: FlakeEnv,
+    },
+    DevShell {
+        #[serde(rename = "devShell")]
+        dev_shell: String,
+    },
+}
+
+impl ToolchainEntry {
+    /// The type of the toolchain derivation, `None` for toolchains from dev shells.
+    pub fn value_type(&self) -> Option<&FlakeValueType> {
+        match self {
+            Self::Package(v) => Some(v),
+            Self::WithEnv { value_type, .. } => Some(value_type),
+            Self::DevShell { .. } => None,
+        }
+    }
+
+    /// Environment variables set for tools of this toolchain.
+    pub fn env(&self) -> Option<&FlakeEnv> {
+        match self {
+            Self::Package(_) | Self::DevShell { .. } => None,
+            Self::WithEnv { env, .. } => Some(env),
+        }
+    }
+
+    /// Attribute path of the derivation, relative to the toolchain attribute.
+    fn package_attr_suffix(&self) -> &'static str {
+        match self {
+            Self::Package(_) | Self::DevShell { .. } => "",
+            Self::WithEnv { .. } => ".package",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum FlakeValueType {
     Derivation,
+
+    /// A toolchain described by a spec, which is built using `mkToolchain` of the config.
+    ToolchainSpec,
     Other(String),
 }
 
@@ -257,11 +783,107 @@ impl<'de> Deserialize<'de> for FlakeValueType {
     where
         D: Deserializer<'de>,
     {
-        let s = <&str>::deserialize(deserializer)?;
+        let s = String::deserialize(deserializer)?;
 
-        match s {
+        match s.as_str() {
             "derivation" => Ok(Self::Derivation),
-            _ => Ok(Self::Other(s.to_string())),
+            "toolchainSpec" => Ok(Self::ToolchainSpec),
+            _ => Ok(Self::Other(s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> FlakeEmbeddedConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn directory_toolchain_uses_longest_prefix() {
+        let config = config(serde_json::json!({
+            "directories": {
+                ".": "stable",
+                "./firmware": "nightly",
+                "firmware/legacy/": "beta",
+            }
+        }));
+        let flake_dir = Path::new("/nonexistent/project");
+        let select = |dir: &str| config.directory_toolchain(flake_dir, &flake_dir.join(dir));
+
+        assert_eq!(select(""), Some("stable"));
+        assert_eq!(select("docs"), Some("stable"));
+        assert_eq!(select("firmware"), Some("nightly"));
+        assert_eq!(select("firmware/src"), Some("nightly"));
+        assert_eq!(select("firmware/legacy"), Some("beta"));
+        assert_eq!(select("firmware/legacy/src"), Some("beta"));
+        assert_eq!(select("firmware-tools"), Some("stable"));
+    }
+
+    #[test]
+    fn directory_toolchain_ignores_directories_outside_of_flake() {
+        let config = config(serde_json::json!({ "directories": { ".": "stable" } }));
+
+        assert_eq!(
+            config.directory_toolchain(
+                Path::new("/nonexistent/project"),
+                Path::new("/nonexistent/other")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn select_toolchain_ranks_tool_above_inherited_directory() {
+        let config = config(serde_json::json!({
+            "toolchains": { "stable": "derivation", "nightly": "derivation" },
+            "toolToolchain": { "cargo-fmt": "nightly" },
+            "directories": { ".": "stable" },
+        }));
+        let invocation = |tool| Invocation {
+            tool,
+            toolchain_override: ToolchainOverride::None,
+            directory_toolchain: Some("stable".into()),
+            remaining_args: Vec::new(),
+        };
+        let flake_dir = Path::new("/nonexistent/project");
+
+        assert_eq!(
+            config.select_toolchain(flake_dir, &invocation(InvokedTool::CargoFmt)),
+            Some(ToolchainSelection::Tool("nightly"))
+        );
+        assert_eq!(
+            config.select_toolchain(flake_dir, &invocation(InvokedTool::Rustc)),
+            Some(ToolchainSelection::Directory("stable"))
+        );
+    }
+
+    #[test]
+    fn resolve_alias_follows_chains() {
+        let config = config(serde_json::json!({
+            "aliases": { "latest": "nightly", "edge": "latest" }
+        }));
+
+        assert_eq!(config.resolve_alias("edge").unwrap(), Some("nightly"));
+        assert_eq!(config.resolve_alias("latest").unwrap(), Some("nightly"));
+        assert_eq!(config.resolve_alias("nightly").unwrap(), None);
+    }
+
+    #[test]
+    fn resolve_alias_detects_cycles() {
+        let config = config(serde_json::json!({
+            "aliases": { "a": "b", "b": "c", "c": "a", "self": "self" }
+        }));
+
+        match config.resolve_alias("a") {
+            Err(Error::AliasCycle { chain }) => assert_eq!(chain, ["a", "b", "c", "a"]),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+        match config.resolve_alias("self") {
+            Err(Error::AliasCycle { chain }) => assert_eq!(chain, ["self", "self"]),
+            other => panic!("expected a cycle, got {:?}", other),
         }
     }
 }
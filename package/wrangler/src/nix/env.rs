@@ -0,0 +1,355 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Environment variables declared in the flake configuration, as reported by the
+/// inspection.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FlakeEnv {
+    /// Variables by name, `None` unsets the variable.
+    #[serde(default)]
+    pub vars: BTreeMap<String, Option<EnvValue>>,
+
+    /// Derivation outputs referenced by the values, as `drv^outputs` installables.
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Set(String),
+    Prepend { prepend: Vec<String> },
+}
+
+/// A derivation whose outputs are made available to tools, like an input of a dev shell.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInput {
+    /// Missing for plain paths, which do not need to be built.
+    pub drv_path: Option<String>,
+
+    /// Output paths by output name.
+    #[serde(default)]
+    pub outputs: BTreeMap<String, PathBuf>,
+}
+
+impl BuildInput {
+    fn is_realised(&self) -> bool {
+        self.outputs.values().all(|path| path.exists())
+    }
+}
+
+impl FlakeEnv {
+    /// Derivations which have to be built before [FlakeEnv::from_build_inputs] can find
+    /// the directories of their outputs.
+    pub fn unrealised_build_inputs(inputs: &[BuildInput]) -> Vec<String> {
+        inputs
+            .iter()
+            .filter(|input| !input.is_realised())
+            .filter_map(|input| input.drv_path.as_ref())
+            .map(|drv_path| format!("{}^*", drv_path))
+            .collect()
+    }
+
+    /// Compute the search paths for the outputs of build inputs, the first input taking
+    /// precedence.
+    ///
+    /// Like for toolchains, only directories which exist are added.
+    pub fn from_build_inputs(inputs: &[BuildInput]) -> Self {
+        const SEARCH_PATHS: [(&str, &[&str]); 4] = [
+            ("PATH", &["bin"]),
+            ("PKG_CONFIG_PATH", &["lib/pkgconfig", "share/pkgconfig"]),
+            ("LIBRARY_PATH", &["lib"]),
+            ("CPATH", &["include"]),
+        ];
+
+        let vars = SEARCH_PATHS
+            .iter()
+            .filter_map(|(name, subdirs)| {
+                let paths = inputs
+                    .iter()
+                    .flat_map(|input| input.outputs.values())
+                    .flat_map(|output| subdirs.iter().map(|subdir| output.join(subdir)))
+                    .filter(|dir| dir.is_dir())
+                    .filter_map(|dir| dir.into_os_string().into_string().ok())
+                    .collect::<Vec<_>>();
+
+                (!paths.is_empty())
+                    .then(|| (name.to_string(), Some(EnvValue::Prepend { prepend: paths })))
+            })
+            .collect();
+
+        Self {
+            vars,
+            requires: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Store paths referenced by the values which do not exist yet, meaning the
+    /// derivations in [FlakeEnv::requires] have to be built first.
+    pub fn missing_store_paths(&self) -> Vec<PathBuf> {
+        self.vars
+            .values()
+            .flatten()
+            .flat_map(|value| match value {
+                EnvValue::Set(v) => std::slice::from_ref(v),
+                EnvValue::Prepend { prepend } => prepend.as_slice(),
+            })
+            .flat_map(|v| store_paths_in(v))
+            .filter(|path| !path.exists())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Apply the variables to a command.
+    ///
+    /// Prepending considers values already set on the command, so the variables of
+    /// multiple environments can be applied in order. Paths which are already present
+    /// are not added again, as nested invocations inherit the environment of the parent.
+    pub fn apply(&self, command: &mut Command) {
+        for (name, value) in &self.vars {
+            match value {
+                None => {
+                    command.env_remove(name);
+                }
+                Some(EnvValue::Set(v)) => {
+                    command.env(name, v);
+                }
+                Some(EnvValue::Prepend { prepend }) => {
+                    let current = Self::current_value(command, name);
+                    let present = current
+                        .as_ref()
+                        .map(|v| std::env::split_paths(v).collect::<Vec<_>>())
+                        .unwrap_or_default();
+                    let new_paths = prepend
+                        .iter()
+                        .map(Path::new)
+                        .filter(|path| !present.iter().any(|v| v == path));
+
+                    command.env(name, crate::util::prepend_paths(current, new_paths));
+                }
+            }
+        }
+    }
+
+    /// The value a variable would have in the spawned command.
+    fn current_value(command: &Command, name: &str) -> Option<OsString> {
+        command
+            .get_envs()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.map(|v| v.to_os_string()))
+            .unwrap_or_else(|| std::env::var_os(name))
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// Find the nix store paths referenced in a string, up to the store object itself.
+fn store_paths_in(value: &str) -> Vec<PathBuf> {
+    const STORE_DIR: &str = "/nix/store/";
+
+    value
+        .match_indices(STORE_DIR)
+        .filter_map(|(start, _)| {
+            let name = &value[start + STORE_DIR.len()..];
+            let end = name
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "+-._?=".contains(c)))
+                .unwrap_or(name.len());
+
+            (end > 0).then(|| PathBuf::from(&value[start..start + STORE_DIR.len() + end]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepend(paths: &[&str]) -> Option<EnvValue> {
+        Some(EnvValue::Prepend {
+            prepend: paths.iter().map(|v| v.to_string()).collect(),
+        })
+    }
+
+    fn command_env(command: &Command, name: &str) -> Option<Option<OsString>> {
+        command
+            .get_envs()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.map(|v| v.to_os_string()))
+    }
+
+    #[test]
+    fn store_paths_in_stops_at_the_store_object() {
+        assert_eq!(
+            store_paths_in("/nix/store/abc-rust-1.80.0/bin:/usr/bin:/nix/store/def-pkg-config"),
+            [
+                PathBuf::from("/nix/store/abc-rust-1.80.0"),
+                PathBuf::from("/nix/store/def-pkg-config"),
+            ]
+        );
+        assert_eq!(
+            store_paths_in("-L/nix/store/abc-openssl/lib -I/nix/store/def-zlib-dev/include"),
+            [
+                PathBuf::from("/nix/store/abc-openssl"),
+                PathBuf::from("/nix/store/def-zlib-dev"),
+            ]
+        );
+        assert!(store_paths_in("/nix/store/").is_empty());
+        assert!(store_paths_in("/usr/lib").is_empty());
+    }
+
+    #[test]
+    fn missing_store_paths_are_deduplicated() {
+        let env = FlakeEnv {
+            vars: [
+                (
+                    "PATH".to_string(),
+                    prepend(&[
+                        "/nix/store/nonexistent-a/bin",
+                        "/nix/store/nonexistent-b/bin",
+                    ]),
+                ),
+                (
+                    "LIBRARY_PATH".to_string(),
+                    Some(EnvValue::Set("/nix/store/nonexistent-a/lib".to_string())),
+                ),
+                ("CC".to_string(), None),
+            ]
+            .into(),
+            requires: Vec::new(),
+        };
+
+        assert_eq!(
+            env.missing_store_paths(),
+            [
+                PathBuf::from("/nix/store/nonexistent-a"),
+                PathBuf::from("/nix/store/nonexistent-b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_prepends_paths_which_are_not_present() {
+        let env = FlakeEnv {
+            vars: [
+                (
+                    "NIX_RUST_WRANGLER_TEST_PATH".to_string(),
+                    prepend(&["/b", "/c"]),
+                ),
+                ("NIX_RUST_WRANGLER_TEST_UNSET".to_string(), None),
+                (
+                    "NIX_RUST_WRANGLER_TEST_SET".to_string(),
+                    Some(EnvValue::Set("value".to_string())),
+                ),
+            ]
+            .into(),
+            requires: Vec::new(),
+        };
+
+        let mut command = Command::new("true");
+        command.env("NIX_RUST_WRANGLER_TEST_PATH", "/a:/b");
+        env.apply(&mut command);
+
+        assert_eq!(
+            command_env(&command, "NIX_RUST_WRANGLER_TEST_PATH"),
+            Some(Some("/c:/a:/b".into()))
+        );
+        assert_eq!(
+            command_env(&command, "NIX_RUST_WRANGLER_TEST_UNSET"),
+            Some(None)
+        );
+        assert_eq!(
+            command_env(&command, "NIX_RUST_WRANGLER_TEST_SET"),
+            Some(Some("value".into()))
+        );
+
+        // Applying again, like in a nested invocation, doesn't add the paths twice
+        env.apply(&mut command);
+        assert_eq!(
+            command_env(&command, "NIX_RUST_WRANGLER_TEST_PATH"),
+            Some(Some("/c:/a:/b".into()))
+        );
+    }
+
+    #[test]
+    fn from_build_inputs_adds_existing_directories_in_order() {
+        let dir = std::env::temp_dir().join(format!(
+            "nix-rust-wrangler-build-inputs-{}",
+            std::process::id()
+        ));
+        let first = dir.join("first");
+        let second = dir.join("second");
+        for subdir in [
+            first.join("bin"),
+            first.join("lib/pkgconfig"),
+            second.join("bin"),
+            second.join("share/pkgconfig"),
+        ] {
+            std::fs::create_dir_all(subdir).unwrap();
+        }
+
+        let input = |path: &Path| BuildInput {
+            drv_path: None,
+            outputs: [("out".to_string(), path.to_path_buf())].into(),
+        };
+        let env = FlakeEnv::from_build_inputs(&[input(&first), input(&second)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let prepended = |name: &str| match &env.vars[name] {
+            Some(EnvValue::Prepend { prepend }) => prepend.clone(),
+            other => panic!("expected paths to prepend, got {:?}", other),
+        };
+        let path = |path: PathBuf| path.into_os_string().into_string().unwrap();
+
+        assert_eq!(
+            prepended("PATH"),
+            [path(first.join("bin")), path(second.join("bin"))]
+        );
+        assert_eq!(
+            prepended("PKG_CONFIG_PATH"),
+            [
+                path(first.join("lib/pkgconfig")),
+                path(second.join("share/pkgconfig"))
+            ]
+        );
+        assert_eq!(prepended("LIBRARY_PATH"), [path(first.join("lib"))]);
+        assert!(!env.vars.contains_key("CPATH"));
+    }
+
+    #[test]
+    fn unrealised_build_inputs_are_built_with_all_outputs() {
+        let inputs = [
+            BuildInput {
+                drv_path: Some("/nix/store/abc-openssl.drv".to_string()),
+                outputs: [
+                    ("out".to_string(), "/nix/store/nonexistent-openssl".into()),
+                    (
+                        "dev".to_string(),
+                        "/nix/store/nonexistent-openssl-dev".into(),
+                    ),
+                ]
+                .into(),
+            },
+            BuildInput {
+                drv_path: Some("/nix/store/def-root.drv".to_string()),
+                outputs: [("out".to_string(), "/".into())].into(),
+            },
+            BuildInput {
+                drv_path: None,
+                outputs: [("out".to_string(), "/nonexistent/path".into())].into(),
+            },
+        ];
+
+        assert_eq!(
+            FlakeEnv::unrealised_build_inputs(&inputs),
+            ["/nix/store/abc-openssl.drv^*"]
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use crate::cache::{CacheLookup, FlakeCache};
+use crate::daemon::{DaemonRequest, DaemonResponse};
+use crate::error::FlakeEvalError;
+use crate::invocation::Invocation;
+use crate::invoker::ToolInvoker;
+use crate::nix::config::FlakeInspection;
+use crate::nix::flake::{BuildTarget, FlakeBuildOutput, NixFlake};
+use crate::nix::process::{output_with_progress, timeout_from_env, LOG_FORMAT_ARGS};
+use crate::nix::NixCommand;
+use crate::util::StableHasher;
+use serde::Deserialize;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::hash::Hasher;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Default for how long a failed evaluation is remembered.
+const DEFAULT_FAILURE_CACHE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Evaluates and builds flake attributes, going through the cache where possible.
+///
+/// With NIX_RUST_WRANGLER_STALE_WHILE_REVALIDATE set, stale cache entries are used
+/// immediately and a detached background process updates the cache for the next
+/// invocation. Otherwise stale entries are re-evaluated before they are used.
+///
+/// Failed evaluations are remembered for NIX_RUST_WRANGLER_FAILURE_CACHE_SECS seconds
+/// (10 by default, 0 disables this), during which the failure is reported again without
+/// invoking nix. This prevents IDEs from hammering nix while the flake is being edited.
+///
+/// If a resolver daemon is running, it is asked first and the cache is only consulted
+/// when the daemon can't answer.
+#[derive(Debug)]
+pub struct FlakeEvaluator<'a> {
+    nix_command: &'a NixCommand,
+    flake: &'a NixFlake,
+    key: String,
+    use_daemon: bool,
+    cache: RefCell<Option<FlakeCache>>,
+    flake_nix_args: OnceCell<Vec<String>>,
+    stale_while_revalidate: bool,
+    failure_cache_window: Duration,
+    revalidation_requested: Cell<bool>,
+}
+
+impl<'a> FlakeEvaluator<'a> {
+    pub fn new(nix_command: &'a NixCommand, flake: &'a NixFlake) -> Self {
+        let cache = FlakeCache::open(flake);
+        if cache.as_ref().is_some_and(FlakeCache::needs_path_ref) {
+            tracing::debug!("Using a path: reference, as remembered by the cache");
+            flake.use_path_ref();
+        }
+
+        let key = cache
+            .as_ref()
+            .map(|cache| cache.key().to_string())
+            .unwrap_or_else(|| FlakeCache::compute_key(flake));
+
+        Self {
+            nix_command,
+            flake,
+            key,
+            // The daemon only watches local flakes
+            use_daemon: flake.remote().is_none(),
+            cache: RefCell::new(cache),
+            flake_nix_args: OnceCell::new(),
+            stale_while_revalidate: crate::util::flag_from_env(
+                "NIX_RUST_WRANGLER_STALE_WHILE_REVALIDATE",
+            ),
+            failure_cache_window: std::env::var("NIX_RUST_WRANGLER_FAILURE_CACHE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_FAILURE_CACHE_WINDOW),
+            revalidation_requested: Cell::new(false),
+        }
+    }
+
+    /// Never ask the daemon, used by the daemon itself.
+    pub fn in_process(mut self) -> Self {
+        self.use_daemon = false;
+        self
+    }
+
+    /// Key identifying the toolchain selected by name in the current flake state, see
+    /// [Invocation::toolchain_name].
+    pub fn resolution_key(flake_key: &str, toolchain_name: Option<&str>) -> String {
+        let mut hasher = StableHasher::new();
+        hasher.write(flake_key.as_bytes());
+        hasher.write(toolchain_name.unwrap_or_default().as_bytes());
+        hasher.finish_hex()
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The nix command extended by the arguments the flake configuration requests.
+    ///
+    /// These can only apply after the flake has been inspected, so they are used for
+    /// builds and dev shells, but not for the inspection itself.
+    pub fn flake_nix_command(&self) -> NixCommand {
+        // Inspecting sets the arguments, so they can't be initialized by the inspection
+        if self.flake_nix_args.get().is_none() {
+            if let Err(err) = self.inspect() {
+                tracing::debug!("Unable to determine nix arguments of the flake: {}", err);
+            }
+        }
+        let args = self.flake_nix_args.get_or_init(Vec::new);
+
+        self.nix_command.with_extra_args(args)
+    }
+
+    pub fn flake(&self) -> &NixFlake {
+        self.flake
+    }
+
+    /// Inspect the flake for its wrangler configuration.
+    pub fn inspect(&self) -> Result<FlakeInspection, FlakeEvalError> {
+        let inspection: FlakeInspection = serde_json::from_value(self.inspect_value()?)?;
+        let _ = self.flake_nix_args.set(inspection.nix_args().to_vec());
+        Ok(inspection)
+    }
+
+    /// Inspect the flake, returning the unparsed inspection result.
+    pub fn inspect_value(&self) -> Result<serde_json::Value, FlakeEvalError> {
+        let daemon_response = self.query_daemon(DaemonRequest::Inspect {
+            flake: self.flake.path().to_path_buf(),
+            key: self.key.clone(),
+        });
+
+        match daemon_response {
+            Some(DaemonResponse::Inspection { value }) if Self::is_usable_inspection(&value) => {
+                tracing::debug!("Using flake evaluation from daemon");
+                return Ok(value);
+            }
+            Some(DaemonResponse::Error { message }) => {
+                tracing::debug!("Daemon failed to resolve: {}", message)
+            }
+            Some(response) => tracing::debug!("Daemon did not resolve: {:?}", response),
+            None => {}
+        }
+
+        let lookup = self
+            .cache
+            .borrow()
+            .as_ref()
+            .map(FlakeCache::inspection)
+            .unwrap_or(CacheLookup::Missing);
+
+        match lookup {
+            CacheLookup::Fresh(value) if Self::is_usable_inspection(&value) => {
+                tracing::debug!("Using cached flake evaluation");
+                return Ok(value);
+            }
+            CacheLookup::Fresh(_) => tracing::debug!("Ignoring unusable cached evaluation"),
+            CacheLookup::Stale { value, age }
+                if self.stale_while_revalidate && Self::is_usable_inspection(&value) =>
+            {
+                tracing::warn!(
+                    "Flake changed, serving stale evaluation from {}s ago while revalidating in the background",
+                    age.as_secs()
+                );
+                self.request_revalidation();
+                return Ok(value);
+            }
+            CacheLookup::Stale { .. } => tracing::debug!("Cached flake evaluation is stale"),
+            CacheLookup::Missing => {}
+        }
+
+        let recent_failure = self
+            .cache
+            .borrow()
+            .as_ref()
+            .and_then(|cache| cache.recent_failure(self.failure_cache_window));
+
+        if let Some(failure) = recent_failure {
+            return Err(FlakeEvalError::CachedFailure {
+                age: failure.age(),
+                message: failure.value.message,
+            });
+        }
+
+        self.evaluate_inspection()
+    }
+
+    /// Build an attribute of the flake, or the result of a builder function of the flake.
+    pub fn build(&self, target: &BuildTarget) -> Result<Vec<FlakeBuildOutput>, FlakeEvalError> {
+        let daemon_response = self.query_daemon(DaemonRequest::Build {
+            flake: self.flake.path().to_path_buf(),
+            key: self.key.clone(),
+            target: target.clone(),
+        });
+
+        match daemon_response {
+            Some(DaemonResponse::Build { outputs }) => {
+                tracing::debug!("Using build of {} from daemon", target);
+                return Ok(outputs);
+            }
+            Some(DaemonResponse::Error { message }) => {
+                tracing::debug!("Daemon failed to resolve: {}", message)
+            }
+            Some(response) => tracing::debug!("Daemon did not resolve: {:?}", response),
+            None => {}
+        }
+
+        let lookup = self
+            .cache
+            .borrow()
+            .as_ref()
+            .map(|cache| cache.build(target))
+            .unwrap_or(CacheLookup::Missing);
+
+        match lookup {
+            CacheLookup::Fresh(outputs) => {
+                tracing::debug!("Using cached build of {}", target);
+                return Ok(outputs);
+            }
+            CacheLookup::Stale { value, age } if self.stale_while_revalidate => {
+                tracing::warn!(
+                    "Flake changed, serving stale build of {} from {}s ago while revalidating in the background",
+                    target,
+                    age.as_secs()
+                );
+                self.request_revalidation();
+                return Ok(value);
+            }
+            CacheLookup::Stale { .. } => tracing::debug!("Cached build of {} is stale", target),
+            CacheLookup::Missing => {}
+        }
+
+        self.evaluate_build(target)
+    }
+
+    /// Build derivation outputs given as `drv^outputs` installables, without linking them.
+    ///
+    /// Unlike flake attributes these are not cached, callers check whether the outputs
+    /// exist already instead.
+    pub fn realise(&self, installables: &[String]) -> Result<(), FlakeEvalError> {
+        if installables.is_empty() {
+            return Ok(());
+        }
+
+        let mut command = self.flake_nix_command().new_command("build");
+        command
+            .arg("--no-link")
+            .args(LOG_FORMAT_ARGS)
+            .args(installables);
+
+        NixFlake::handle_nix_output(output_with_progress(
+            command,
+            timeout_from_env("NIX_RUST_WRANGLER_BUILD_TIMEOUT_SECS"),
+        )?)
+        .map(|_| ())
+    }
+
+    /// Remember the toolchain resolved for a toolchain name, to fall back to it when
+    /// offline. The default toolchain uses an empty name.
+    pub fn store_last_good(&self, toolchain_name: &str, toolchain_dir: &Path) {
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.store_last_good(toolchain_name, toolchain_dir);
+        }
+    }
+
+    /// Create an invoker using the toolchain last resolved for the invocation, regardless
+    /// of whether the flake changed since.
+    ///
+    /// The toolchain is selected like [FlakeInspection::make_invoker] does, using the last
+    /// cached inspection if the flake could not be inspected.
+    ///
+    /// This is only done in offline mode (NIX_RUST_WRANGLER_OFFLINE), where nix failing
+    /// is expected if anything needs to be fetched.
+    pub fn last_known_good_invoker(
+        &self,
+        invocation: &Invocation,
+        inspection: Option<&FlakeInspection>,
+        err: &dyn std::fmt::Display,
+    ) -> Option<ToolInvoker> {
+        if !crate::util::flag_from_env("NIX_RUST_WRANGLER_OFFLINE") {
+            return None;
+        }
+
+        let cached_inspection = match inspection {
+            Some(_) => None,
+            None => self.cached_inspection(),
+        };
+        let toolchain_name = match inspection.or(cached_inspection.as_ref()) {
+            Some(inspection) => inspection.selected_toolchain_name(self.flake.dir(), invocation),
+            None => invocation.toolchain_name().unwrap_or_default(),
+        };
+
+        let last_good = self
+            .cache
+            .borrow()
+            .as_ref()
+            .and_then(|cache| cache.last_good(toolchain_name))?;
+
+        match ToolInvoker::from_toolchain_dir(&last_good.value, invocation, None) {
+            Ok(invoker) => {
+                tracing::warn!(
+                    "Nix failed while offline, using toolchain {} last resolved {}s ago: {}",
+                    last_good.value.display(),
+                    last_good.age().as_secs(),
+                    err
+                );
+                Some(invoker)
+            }
+            Err(err) => {
+                tracing::debug!("Unable to use last known good toolchain: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Re-evaluate the flake and rebuild all previously built attributes, updating the
+    /// cache with the results.
+    pub fn revalidate(&self) -> Result<(), FlakeEvalError> {
+        let Some(lock) = self
+            .cache
+            .borrow()
+            .as_ref()
+            .and_then(FlakeCache::try_lock_revalidation)
+        else {
+            tracing::info!("Cache is disabled or already being revalidated");
+            return Ok(());
+        };
+
+        self.evaluate_inspection()?;
+
+        let targets = self
+            .cache
+            .borrow()
+            .as_ref()
+            .map(FlakeCache::cached_build_targets)
+            .unwrap_or_default();
+
+        for target in targets {
+            if let Err(err) = self.evaluate_build(&target) {
+                tracing::warn!("Failed to rebuild {}: {}", target, err);
+            }
+        }
+
+        drop(lock);
+        Ok(())
+    }
+
+    fn query_daemon(&self, request: DaemonRequest) -> Option<DaemonResponse> {
+        if self.use_daemon {
+            crate::daemon::query(&request)
+        } else {
+            None
+        }
+    }
+
+    /// The last inspection stored in the cache, even if it is stale.
+    fn cached_inspection(&self) -> Option<FlakeInspection> {
+        let value = match self.cache.borrow().as_ref()?.inspection() {
+            CacheLookup::Fresh(value) | CacheLookup::Stale { value, .. } => value,
+            CacheLookup::Missing => return None,
+        };
+
+        FlakeInspection::deserialize(value).ok()
+    }
+
+    fn is_usable_inspection(value: &serde_json::Value) -> bool {
+        FlakeInspection::deserialize(value).is_ok()
+    }
+
+    fn evaluate_inspection(&self) -> Result<serde_json::Value, FlakeEvalError> {
+        let used_path_ref = self.flake.uses_path_ref();
+        let value = match self.flake.apply_expr_json::<serde_json::Value>(
+            self.nix_command,
+            ".",
+            FlakeInspection::apply_expr(crate::nix::target_system()),
+        ) {
+            Ok(v) => v,
+            Err(FlakeEvalError::EvalFailed {
+                status,
+                stdout,
+                stderr,
+            }) => {
+                if let Some(cache) = self.cache.borrow_mut().as_mut() {
+                    cache.store_failure(stderr.trim().to_string());
+                }
+
+                return Err(FlakeEvalError::EvalFailed {
+                    status,
+                    stdout,
+                    stderr,
+                });
+            }
+            Err(err) => return Err(err),
+        };
+
+        // Parse before storing, so that unusable results never end up in the cache
+        FlakeInspection::deserialize(&value)?;
+
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.store_inspection(value.clone());
+        }
+
+        self.store_path_ref_fallback(used_path_ref);
+        Ok(value)
+    }
+
+    fn evaluate_build(
+        &self,
+        target: &BuildTarget,
+    ) -> Result<Vec<FlakeBuildOutput>, FlakeEvalError> {
+        let used_path_ref = self.flake.uses_path_ref();
+        let outputs = self.flake.build(&self.flake_nix_command(), target)?;
+
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.store_build(target, outputs.clone());
+        }
+
+        self.store_path_ref_fallback(used_path_ref);
+        Ok(outputs)
+    }
+
+    /// Remember switching to a path: reference while running nix, so that later
+    /// invocations use it right away instead of failing first.
+    fn store_path_ref_fallback(&self, used_path_ref: bool) {
+        if used_path_ref || !self.flake.uses_path_ref() {
+            return;
+        }
+
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.store_path_ref();
+        }
+    }
+
+    /// Spawn a background process which revalidates the cache, at most once per invocation.
+    fn request_revalidation(&self) {
+        if self.revalidation_requested.replace(true) {
+            return;
+        }
+
+        let own_executable = match std::env::current_exe() {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::warn!("Unable to start background revalidation: {}", err);
+                return;
+            }
+        };
+
+        // The spawned process immediately detaches another copy of itself and exits,
+        // so waiting here is quick and does not leave a zombie behind.
+        let mut command = Command::new(own_executable);
+        if self.flake.uses_path_ref() {
+            command.env("NIX_RUST_WRANGLER_PATH_FLAKE_REF", "1");
+        }
+
+        let status = command
+            .arg("nix-revalidate-cache")
+            .env("NIX_RUST_WRANGLER_FLAKE_PATH", self.flake.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+
+        if let Err(err) = status {
+            tracing::warn!("Unable to start background revalidation: {}", err);
+        }
+    }
+}
+
+/// Entry point of the background revalidation process.
+///
+/// The first process detaches a copy of itself into its own process group, so that it
+/// survives the invoking tool and does not receive its signals. Nix and the flake are
+/// only looked up by the detached copy, so the invoking tool does not wait for them.
+pub fn run_revalidation(find_nix: impl FnOnce() -> Option<NixCommand>) -> ! {
+    if !crate::util::flag_from_env("NIX_RUST_WRANGLER_REVALIDATE_DETACHED") {
+        let spawn_result = std::env::current_exe().and_then(|own_executable| {
+            Command::new(own_executable)
+                .arg("nix-revalidate-cache")
+                .env("NIX_RUST_WRANGLER_REVALIDATE_DETACHED", "1")
+                .stdin(Stdio::null())
+                .process_group(0)
+                .spawn()
+        });
+
+        if let Err(err) = spawn_result {
+            tracing::error!("Failed to detach revalidation process: {}", err);
+            std::process::exit(1);
+        }
+
+        std::process::exit(0);
+    }
+
+    let (Some(nix_command), Some(flake)) = (find_nix(), NixFlake::find_automatically()) else {
+        tracing::error!("Revalidation requires nix and a flake");
+        std::process::exit(1);
+    };
+
+    tracing::info!("Revalidating cache for flake at {}", flake.path().display());
+    if let Err(err) = FlakeEvaluator::new(&nix_command, &flake).revalidate() {
+        tracing::error!("Failed to revalidate flake: {}", err);
+        std::process::exit(1);
+    }
+
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolution_key_depends_on_flake_and_toolchain() {
+        let key = FlakeEvaluator::resolution_key("82eb338e172db401", Some("nightly"));
+
+        assert_eq!(
+            key,
+            FlakeEvaluator::resolution_key("82eb338e172db401", Some("nightly"))
+        );
+        assert_ne!(
+            key,
+            FlakeEvaluator::resolution_key("82eb338e172db401", Some("stable"))
+        );
+        assert_ne!(
+            key,
+            FlakeEvaluator::resolution_key("ea66565b687e5ae5", Some("nightly"))
+        );
+
+        // The default toolchain is selected without a name
+        assert_ne!(
+            key,
+            FlakeEvaluator::resolution_key("82eb338e172db401", None)
+        );
+        assert_eq!(
+            FlakeEvaluator::resolution_key("82eb338e172db401", None),
+            FlakeEvaluator::resolution_key("82eb338e172db401", Some(""))
+        );
+    }
+}
@@ -1,70 +1,141 @@
 use crate::error::FlakeEvalError;
+use crate::nix::process::{
+    output_with_progress, output_with_timeout, timeout_from_env, LOG_FORMAT_ARGS,
+};
+use crate::nix::project::NixProject;
 use crate::nix::NixCommand;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 use std::process::Output;
 
+/// Name of the marker file pointing a project at a flake somewhere else.
+pub const FLAKE_REF_MARKER: &str = ".rust-wrangler-flake";
+
 #[derive(Debug, Clone)]
 pub struct NixFlake {
     flake_path: PathBuf,
     flake_dir: PathBuf,
+
+    /// Reference to a flake which is not a local flake.nix, for example a central
+    /// toolchain flake.
+    remote: Option<RemoteFlakeRef>,
+
+    /// Refer to the flake using a path: reference instead of a plain path.
+    ///
+    /// Plain paths inside a git repository are treated as git flakes by nix, which only
+    /// see files tracked by git. This is switched on once nix fails because of that, or
+    /// if the cache remembers that it did for the current state of the flake.
+    use_path_ref: Cell<bool>,
 }
 
 impl NixFlake {
-    /// Search the tree upwards from the process working directory to find a flake.nix file.
+    /// Search the tree upwards from the process working directory to find a nix project,
+    /// and use it if it is a flake.
     /// If NIX_RUST_WRANGLER_FLAKE_PATH is set, use that as the flake path.
     pub fn find_automatically() -> Option<Self> {
-        if let Some(flake_path) = std::env::var_os("NIX_RUST_WRANGLER_FLAKE_PATH") {
-            let flake_path = PathBuf::from(flake_path);
-            let flake_dir = flake_path
-                .parent()
-                .unwrap_or_else(|| Path::new("/"))
-                .to_path_buf();
-
-            return Some(Self {
-                flake_path,
-                flake_dir,
-            });
+        NixProject::find_automatically().and_then(NixProject::into_flake)
+    }
+
+    /// Use the flake specified by NIX_RUST_WRANGLER_FLAKE_PATH, if set.
+    ///
+    /// This is either the path of a flake.nix, the path of a marker file or a flake
+    /// reference such as `github:owner/repo#attr`.
+    pub fn from_env() -> Option<Self> {
+        let flake_path = PathBuf::from(std::env::var_os("NIX_RUST_WRANGLER_FLAKE_PATH")?);
+
+        if let Some(reference) = flake_path
+            .to_str()
+            .filter(|v| RemoteFlakeRef::is_flake_ref(v))
+            .and_then(RemoteFlakeRef::parse)
+        {
+            let flake_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+            return Some(Self::new(flake_path, flake_dir, Some(reference)));
+        }
+
+        let flake_dir = flake_path
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .to_path_buf();
+
+        if flake_path.file_name() == Some(OsStr::new(FLAKE_REF_MARKER)) {
+            return Self::from_marker(flake_path, flake_dir);
         }
 
-        let pwd = std::env::current_dir()
+        Some(Self::new(flake_path, flake_dir, None))
+    }
+
+    /// Use the flake referenced by the given reference, on behalf of the given directory.
+    pub fn from_reference(reference: &str, dir: &Path) -> Option<Self> {
+        RemoteFlakeRef::parse(reference)
+            .map(|remote| Self::new(PathBuf::from(reference), dir.to_path_buf(), Some(remote)))
+    }
+
+    fn from_marker(marker_path: PathBuf, dir: PathBuf) -> Option<Self> {
+        let content = std::fs::read_to_string(&marker_path)
             .map_err(|err| {
-                tracing::warn!("Failed to determine current working directory: {}", err);
+                tracing::warn!("Failed to read {}: {}", marker_path.display(), err);
                 err
             })
             .ok()?;
 
-        if let Some(flake) = Self::find(&pwd) {
-            return Some(flake);
+        // The first line which is not empty and not a comment holds the reference
+        let reference = content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(RemoteFlakeRef::parse);
+
+        match reference {
+            Some(reference) => Some(Self::new(marker_path, dir, Some(reference))),
+            None => {
+                tracing::warn!(
+                    "{} does not contain a flake reference",
+                    marker_path.display()
+                );
+                None
+            }
         }
+    }
 
-        // Last resort: search upwards of our own executable
-        let exe = PathBuf::from(std::env::args_os().next()?);
-        tracing::debug!("Attempting to find flake relative to own executable: {}", exe.display());
-        
-        let exe_dir = exe.parent()?;
-        Self::find(exe_dir)
+    fn new(flake_path: PathBuf, flake_dir: PathBuf, remote: Option<RemoteFlakeRef>) -> Self {
+        Self {
+            flake_path,
+            flake_dir,
+            remote,
+            use_path_ref: Cell::new(crate::util::flag_from_env(
+                "NIX_RUST_WRANGLER_PATH_FLAKE_REF",
+            )),
+        }
     }
 
-    /// Search the tree upwards from the given path to find a flake.nix file.
-    pub fn find(start: &Path) -> Option<Self> {
-        let mut current = start;
-        loop {
-            let flake_path = current.join("flake.nix");
-            tracing::trace!("Checking for flake.nix at {:?}", flake_path);
-
-            if flake_path.is_file() {
-                tracing::debug!("Found flake.nix at {:?}", flake_path);
-                return Some(Self {
-                    flake_path,
-                    flake_dir: current.to_path_buf(),
-                });
-            }
+    /// Use the flake.nix file inside the given directory, or the flake referenced by a
+    /// marker file, if there is one.
+    pub fn in_dir(dir: &Path) -> Option<Self> {
+        let flake_path = dir.join("flake.nix");
+        tracing::trace!("Checking for flake.nix at {:?}", flake_path);
 
-            current = current.parent()?;
+        if flake_path.is_file() {
+            tracing::debug!("Found flake.nix at {:?}", flake_path);
+            return Some(Self::new(flake_path, dir.to_path_buf(), None));
         }
+
+        let marker_path = dir.join(FLAKE_REF_MARKER);
+        if marker_path.is_file() {
+            tracing::debug!("Found flake reference marker at {:?}", marker_path);
+            return Self::from_marker(marker_path, dir.to_path_buf());
+        }
+
+        None
+    }
+
+    /// Search the tree upwards from the given path to find a flake.nix or marker file.
+    pub fn find(start: &Path) -> Option<Self> {
+        start.ancestors().find_map(Self::in_dir)
     }
 
     pub fn path(&self) -> &Path {
@@ -75,6 +146,29 @@ impl NixFlake {
         &self.flake_dir
     }
 
+    /// The reference to a flake outside the project, if this is not a local flake.
+    pub fn remote(&self) -> Option<&RemoteFlakeRef> {
+        self.remote.as_ref()
+    }
+
+    /// Identifies the flake in the cache, flakes with the same identity share results.
+    pub fn cache_identity(&self) -> OsString {
+        match &self.remote {
+            Some(remote) => OsString::from(remote.to_string()),
+            None => self.flake_dir.clone().into_os_string(),
+        }
+    }
+
+    /// Refer to the flake using a path: reference from now on.
+    pub fn use_path_ref(&self) {
+        self.use_path_ref.set(true);
+    }
+
+    /// Whether the flake is referred to using a path: reference.
+    pub fn uses_path_ref(&self) -> bool {
+        self.use_path_ref.get()
+    }
+
     /// Apply a nix expression to the flake and return the result as JSON.
     pub fn apply_expr_json<T: serde::de::DeserializeOwned>(
         &self,
@@ -82,40 +176,133 @@ impl NixFlake {
         attr: impl AsRef<str>,
         expr: impl AsRef<str>,
     ) -> Result<T, FlakeEvalError> {
-        let installable_expression = self.installable(attr);
-
-        tracing::trace!("Evaluating flake expression: {:?}", installable_expression);
+        let eval_output = self.run_with_ref_fallback(attr.as_ref(), |installable_expression| {
+            tracing::trace!("Evaluating flake expression: {:?}", installable_expression);
 
-        let eval_output = nix_command
-            .new_command()
-            .args(["eval", "--json"])
-            .arg(installable_expression)
-            .args(["--apply", expr.as_ref()])
-            .output()?;
+            let mut command = nix_command.new_command("eval");
+            command
+                .arg("--json")
+                .arg(installable_expression)
+                .args(["--apply", expr.as_ref()]);
+            output_with_timeout(
+                command,
+                timeout_from_env("NIX_RUST_WRANGLER_EVAL_TIMEOUT_SECS"),
+            )
+        })?;
 
-        let eval_output = Self::handle_nix_output(eval_output)?;
         serde_json::from_slice::<T>(&eval_output).map_err(Into::into)
     }
 
+    /// Build an attribute of the flake, or the result of a builder function of the flake.
     pub fn build(
         &self,
         nix_command: &NixCommand,
-        attr: impl AsRef<str>,
+        target: &BuildTarget,
+    ) -> Result<Vec<FlakeBuildOutput>, FlakeEvalError> {
+        let attr = match target {
+            BuildTarget::Attr(attr) => attr,
+            BuildTarget::Applied { builder, argument } => {
+                return self.build_applied(nix_command, builder, argument);
+            }
+        };
+
+        let build_output = self.run_with_ref_fallback(attr, |installable_expression| {
+            tracing::trace!("Building flake expression: {:?}", installable_expression);
+
+            // Builds can take a long time, so report what nix is doing
+            let mut command = nix_command.new_command("build");
+            command
+                .args(["--no-link", "--json"])
+                .args(LOG_FORMAT_ARGS)
+                .arg(installable_expression);
+            output_with_progress(
+                command,
+                timeout_from_env("NIX_RUST_WRANGLER_BUILD_TIMEOUT_SECS"),
+            )
+        })?;
+
+        serde_json::from_slice::<Vec<FlakeBuildOutput>>(&build_output).map_err(Into::into)
+    }
+
+    /// Build the derivation a function of the flake returns for the value of an attribute.
+    ///
+    /// The derivation can't be built as an installable of the flake, so it is instantiated
+    /// by evaluating the application and then built by its derivation path, including all
+    /// of its outputs.
+    fn build_applied(
+        &self,
+        nix_command: &NixCommand,
+        builder: &str,
+        argument: &str,
     ) -> Result<Vec<FlakeBuildOutput>, FlakeEvalError> {
-        let installable_expression = self.installable(attr);
-        tracing::trace!("Building flake expression: {:?}", installable_expression);
+        let drv_path: String = self.apply_expr_json(
+            nix_command,
+            ".",
+            format!(
+                "outputs: (outputs.{} outputs.{}).drvPath",
+                builder, argument
+            ),
+        )?;
+        tracing::debug!("Building {} applied to {}: {}", builder, argument, drv_path);
 
-        let build_output = nix_command
-            .new_command()
-            .args(["build", "--no-link", "--json"])
-            .arg(installable_expression)
-            .output()?;
+        let mut command = nix_command.new_command("build");
+        command
+            .args(["--no-link", "--json"])
+            .args(LOG_FORMAT_ARGS)
+            .arg(format!("{}^*", drv_path));
+        let build_output = Self::handle_nix_output(output_with_progress(
+            command,
+            timeout_from_env("NIX_RUST_WRANGLER_BUILD_TIMEOUT_SECS"),
+        )?)?;
 
-        let build_output = Self::handle_nix_output(build_output)?;
         serde_json::from_slice::<Vec<FlakeBuildOutput>>(&build_output).map_err(Into::into)
     }
 
-    fn handle_nix_output(output: Output) -> Result<Vec<u8>, FlakeEvalError> {
+    /// Run a nix command on an installable of the flake, retrying with a path: reference
+    /// if nix failed because of files which are not tracked by git.
+    fn run_with_ref_fallback(
+        &self,
+        attr: &str,
+        run_command: impl Fn(OsString) -> Result<Output, FlakeEvalError>,
+    ) -> Result<Vec<u8>, FlakeEvalError> {
+        let result = Self::handle_nix_output(run_command(self.installable(attr))?);
+
+        match result {
+            Err(FlakeEvalError::EvalFailed { ref stderr, .. })
+                if self.remote.is_none()
+                    && !self.use_path_ref.get()
+                    && Self::is_untracked_file_error(stderr) =>
+            {
+                tracing::warn!(
+                    "Flake at {} uses files which are not tracked by git, retrying with a path: \
+                    reference. Use git add to avoid copying the entire directory to the store.",
+                    self.flake_dir.display()
+                );
+
+                self.use_path_ref.set(true);
+                Self::handle_nix_output(run_command(self.installable(attr))?)
+            }
+            result => result,
+        }
+    }
+
+    /// Check whether nix failed because the flake is a git flake, which does not contain
+    /// files that have not been added to git yet.
+    ///
+    /// Only recent versions of nix name the untracked file. Older versions and Lix fail
+    /// to find the file in the copy of the flake in the store, or the flake itself if
+    /// `flake.nix` is not tracked yet.
+    fn is_untracked_file_error(stderr: &str) -> bool {
+        stderr.contains("is not tracked by Git")
+            || stderr.contains("does not contain a '/flake.nix' file")
+            || stderr.lines().any(|line| {
+                line.contains("getting status of '/nix/store/")
+                    && line.contains("-source/")
+                    && line.contains("No such file or directory")
+            })
+    }
+
+    pub fn handle_nix_output(output: Output) -> Result<Vec<u8>, FlakeEvalError> {
         tracing::debug!("Nix exited with status: {:?}", output.status);
         tracing::debug!("Nix stdout: {}", String::from_utf8_lossy(&output.stdout));
         tracing::debug!("Nix stderr: {}", String::from_utf8_lossy(&output.stderr));
@@ -131,17 +318,288 @@ impl NixFlake {
         Ok(output.stdout)
     }
 
+    /// The reference to the flake itself, as understood by nix.
+    ///
+    /// Plain paths can not be escaped, so a path: reference is used if the directory
+    /// contains characters with a special meaning in flake references.
+    pub fn reference(&self) -> OsString {
+        if let Some(remote) = &self.remote {
+            return OsString::from(&remote.reference);
+        }
+
+        let dir = self.flake_dir.as_os_str();
+        let needs_escaping = dir.to_str().is_none()
+            || dir
+                .as_bytes()
+                .iter()
+                .any(|b| b.is_ascii_whitespace() || b"#?%".contains(b));
+
+        if self.use_path_ref.get() || needs_escaping {
+            let mut reference = OsString::from("path:");
+            reference.push(percent_encode(dir, |b| {
+                b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&b)
+            }));
+            reference
+        } else {
+            dir.to_os_string()
+        }
+    }
+
     pub fn installable(&self, attr: impl AsRef<str>) -> OsString {
-        let mut installable_expression = OsString::from(self.flake_dir.clone());
+        // Remote references may point at an attribute containing the outputs to use
+        let attr = match self.remote.as_ref().and_then(|remote| remote.attr.as_ref()) {
+            Some(base) if attr.as_ref() == "." => base.clone(),
+            Some(base) => format!("{}.{}", base, attr.as_ref()),
+            None => attr.as_ref().to_string(),
+        };
+
+        let mut installable_expression = self.reference();
         installable_expression.push("#");
-        installable_expression.push(attr.as_ref());
+        installable_expression.push(percent_encode(OsStr::new(&attr), |b| {
+            b.is_ascii_graphic() && !b"%#?{}[]<>\\|`".contains(&b)
+        }));
         installable_expression
     }
+
+    /// The installable of a dev shell of the flake, or the default one.
+    pub fn dev_shell_installable(&self, shell_name: Option<&str>) -> OsString {
+        // nix only looks up dev shells by name at the root of a flake
+        if self
+            .remote
+            .as_ref()
+            .is_some_and(|remote| remote.attr.is_some())
+        {
+            return self.installable(format!(
+                "devShells.{}.{}",
+                crate::nix::target_system(),
+                shell_name.unwrap_or("default")
+            ));
+        }
+
+        match shell_name {
+            Some(shell_name) => self.installable(shell_name),
+            None => self.reference(),
+        }
+    }
+}
+
+/// A flake reference with an optional attribute, such as `github:owner/repo#attr`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RemoteFlakeRef {
+    pub reference: String,
+
+    /// Attribute of the flake which contains the outputs to use instead of the root.
+    pub attr: Option<String>,
+}
+
+impl RemoteFlakeRef {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (reference, attr) = match value.trim().split_once('#') {
+            Some((reference, attr)) => (reference, Some(attr).filter(|v| !v.is_empty())),
+            None => (value.trim(), None),
+        };
+
+        if reference.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            reference: reference.to_string(),
+            attr: attr.map(String::from),
+        })
+    }
+
+    /// Check whether a value is a flake reference rather than a path.
+    pub fn is_flake_ref(value: &str) -> bool {
+        !value.starts_with('/') && !value.starts_with('.') && value.contains(':')
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Display for RemoteFlakeRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.attr {
+            Some(attr) => write!(f, "{}#{}", self.reference, attr),
+            None => write!(f, "{}", self.reference),
+        }
+    }
+}
+
+/// Percent-encode all bytes not accepted by the given predicate.
+fn percent_encode(value: &OsStr, keep: impl Fn(u8) -> bool) -> OsString {
+    let mut encoded = Vec::with_capacity(value.len());
+    for b in value.as_bytes() {
+        if keep(*b) {
+            encoded.push(*b);
+        } else {
+            encoded.extend_from_slice(format!("%{:02X}", b).as_bytes());
+        }
+    }
+
+    OsString::from_vec(encoded)
+}
+
+/// Something the flake can build, which is cached by the evaluator.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BuildTarget {
+    /// An attribute of the flake outputs.
+    Attr(String),
+
+    /// The result of applying the function at the attribute `builder` to the value at the
+    /// attribute `argument`.
+    Applied { builder: String, argument: String },
+}
+
+impl Display for BuildTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Attr(attr) => write!(f, "{}", attr),
+            Self::Applied { builder, argument } => write!(f, "{} applied to {}", builder, argument),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FlakeBuildOutput {
     pub drv_path: PathBuf,
     pub outputs: HashMap<String, PathBuf>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_flake(dir: &str) -> NixFlake {
+        NixFlake::new(Path::new(dir).join("flake.nix"), PathBuf::from(dir), None)
+    }
+
+    #[test]
+    fn percent_encode_keeps_accepted_bytes() {
+        let encoded = percent_encode(OsStr::new("a b%c/d"), |b| b.is_ascii_alphanumeric());
+        assert_eq!(encoded, "a%20b%25c%2Fd");
+
+        let non_utf8 = OsStr::from_bytes(b"x\xff");
+        assert_eq!(percent_encode(non_utf8, |_| false), "%78%FF");
+    }
+
+    #[test]
+    fn installable_escapes_special_directories() {
+        assert_eq!(
+            local_flake("/src/project").installable("."),
+            "/src/project#."
+        );
+        assert_eq!(
+            local_flake("/src/my project#1").installable("."),
+            "path:/src/my%20project%231#."
+        );
+        assert_eq!(
+            local_flake("/src/project").installable(r#"a."b c""#),
+            "/src/project#a.\"b%20c\""
+        );
+    }
+
+    #[test]
+    fn installable_uses_path_ref_once_switched() {
+        let flake = local_flake("/src/project");
+        flake.use_path_ref();
+        assert_eq!(
+            flake.installable("devShells"),
+            "path:/src/project#devShells"
+        );
+    }
+
+    #[test]
+    fn remote_flake_ref_parse_splits_attr() {
+        let remote = RemoteFlakeRef::parse(" github:owner/repo#toolchains.stable \n").unwrap();
+        assert_eq!(remote.reference, "github:owner/repo");
+        assert_eq!(remote.attr.as_deref(), Some("toolchains.stable"));
+        assert_eq!(remote.to_string(), "github:owner/repo#toolchains.stable");
+
+        let remote = RemoteFlakeRef::parse("github:owner/repo#").unwrap();
+        assert_eq!(remote.attr, None);
+        assert_eq!(remote.to_string(), "github:owner/repo");
+
+        assert!(RemoteFlakeRef::parse("  ").is_none());
+        assert!(RemoteFlakeRef::parse("#attr").is_none());
+    }
+
+    #[test]
+    fn remote_flake_ref_installable_is_relative_to_attr() {
+        let remote = RemoteFlakeRef::parse("github:owner/repo#wrangler").unwrap();
+        let flake = NixFlake::new(
+            PathBuf::from("github:owner/repo#wrangler"),
+            "/src".into(),
+            Some(remote),
+        );
+
+        assert_eq!(flake.installable("."), "github:owner/repo#wrangler");
+        assert_eq!(
+            flake.installable("rustWrangler"),
+            "github:owner/repo#wrangler.rustWrangler"
+        );
+    }
+
+    #[test]
+    fn is_flake_ref_distinguishes_paths() {
+        assert!(RemoteFlakeRef::is_flake_ref("github:owner/repo"));
+        assert!(RemoteFlakeRef::is_flake_ref(
+            "git+https://example.com/repo?ref=main"
+        ));
+        assert!(!RemoteFlakeRef::is_flake_ref("/src/project/flake.nix"));
+        assert!(!RemoteFlakeRef::is_flake_ref("./flake.nix"));
+        assert!(!RemoteFlakeRef::is_flake_ref("flake.nix"));
+    }
+
+    #[test]
+    fn is_untracked_file_error_matches_messages_of_nix_versions() {
+        assert!(NixFlake::is_untracked_file_error(
+            "error: Path 'toolchain.nix' in the repository \"/src/project\" is not tracked by Git."
+        ));
+        assert!(NixFlake::is_untracked_file_error(
+            "error: source tree referenced by 'git+file:///src/project' does not contain a '/flake.nix' file"
+        ));
+        assert!(NixFlake::is_untracked_file_error(
+            "error: getting status of '/nix/store/0c5x3qxm6xdzqbm3sdlcsadjxbk9ilrk-source/toolchain.nix': No such file or directory"
+        ));
+
+        assert!(!NixFlake::is_untracked_file_error(
+            "error: getting status of '/src/project/toolchain.nix': No such file or directory"
+        ));
+        assert!(!NixFlake::is_untracked_file_error(
+            "error: attribute 'toolchain' missing"
+        ));
+    }
+
+    #[test]
+    fn build_target_display_names_the_attributes() {
+        assert_eq!(
+            BuildTarget::Attr("rustWrangler.toolchain".into()).to_string(),
+            "rustWrangler.toolchain"
+        );
+        assert_eq!(
+            BuildTarget::Applied {
+                builder: "rustWrangler.mkToolchain".into(),
+                argument: "rustWrangler.toolchains.nightly".into(),
+            }
+            .to_string(),
+            "rustWrangler.mkToolchain applied to rustWrangler.toolchains.nightly"
+        );
+    }
+
+    #[test]
+    fn build_target_roundtrips_through_json() {
+        let targets = [
+            BuildTarget::Attr("a.\"b.c\"".into()),
+            BuildTarget::Applied {
+                builder: "a.mkToolchain".into(),
+                argument: "a.toolchains.\"1.70\"".into(),
+            },
+        ];
+
+        for target in targets {
+            let json = serde_json::to_string(&target).unwrap();
+            assert_eq!(serde_json::from_str::<BuildTarget>(&json).unwrap(), target);
+        }
+    }
+}
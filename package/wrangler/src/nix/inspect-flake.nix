@@ -1,12 +1,19 @@
-# Called using nix eval --apply
+# Called using nix eval --apply, after the system to inspect for has been applied
+targetSystem:
 let
-  targetSystem = "x86_64-linux";
-
   determineType = value:
     if value ? type
       then value.type
       else builtins.typeOf value;
 
+  /**
+    Determine the type of a value which may fail to evaluate, like a dev shell which is
+    broken or restricted to other platforms. Such values are reported as "error".
+   */
+  tryDetermineType = value:
+    let result = builtins.tryEval (determineType value); in
+    if result.success then result.value else "error";
+
   /**
     Apply a function to an attribute if it exists, otherwise return an empty set.
    */
@@ -18,27 +25,295 @@ let
     else
       {};
 
+  /**
+    Process declared environment variables.
+
+    Values are reported as strings to set, lists of paths to prepend or null to unset.
+    Derivations the values refer to are reported as well, as they may still have to be
+    built before the variables can be used.
+   */
+  processEnv = env:
+    let
+      toList = value: if builtins.isList value then value else [ value ];
+
+      normalize = value:
+        if value == null then null
+        else if builtins.isAttrs value && value ? prepend && !(value ? outPath)
+          then { prepend = map toString (toList value.prepend); }
+        else toString value;
+
+      vars = builtins.mapAttrs (_: normalize) env;
+
+      strings = builtins.concatMap (value:
+        if value == null then []
+        else if builtins.isAttrs value then value.prepend
+        else [ value ]) (builtins.attrValues vars);
+
+      requires = builtins.concatMap (string:
+        let context = builtins.getContext string; in
+        builtins.concatMap (path:
+          if context.${path} ? outputs
+            then [ "${path}^${builtins.concatStringsSep "," context.${path}.outputs}" ]
+            else []) (builtins.attrNames context)) strings;
+    in
+    { inherit vars requires; };
+
+  /**
+    Process a build input, reporting its output paths without building it.
+   */
+  processBuildInput = input:
+    if input ? drvPath
+      then {
+        inherit (input) drvPath;
+        outputs = builtins.listToAttrs (map (name: {
+          inherit name;
+          value = input.${name}.outPath;
+        }) (input.outputs or [ "out" ]));
+      }
+      else { outputs = { out = toString input; }; };
+
+  isToolchainSpecSet = value: builtins.isAttrs value && !(value ? type);
+
+  /**
+    Whether a value is a toolchain spec, like a channel name or an attribute set
+    describing the toolchain, which is turned into a derivation by mkToolchain.
+   */
+  isToolchainSpec = value:
+    builtins.isString value ||
+      (isToolchainSpecSet value &&
+        builtins.all (name: toolchainSpecSchema ? ${name}) (builtins.attrNames value));
+
+  /**
+    Determine the type of a toolchain package, reporting specs if the configuration
+    provides a builder for them.
+   */
+  determinePackageType = hasBuilder: value:
+    if hasBuilder && isToolchainSpec value
+      then "toolchainSpec"
+      else determineType value;
+
+  /**
+    Process a toolchain, which is either a derivation or spec, an attribute set with
+    the package and the environment variables to set for it, or an attribute set
+    naming the dev shell providing the toolchain.
+   */
+  processToolchain = hasBuilder: toolchain:
+    if builtins.isAttrs toolchain && !(toolchain ? type) && toolchain ? package
+      then {
+        type = determinePackageType hasBuilder toolchain.package;
+        env = let env = toolchain.env or {}; in
+          processEnv (if checkEnv "env" env == [] then env else {});
+      }
+    else if builtins.isAttrs toolchain && !(toolchain ? type) && builtins.isString (toolchain.devShell or null)
+      then { inherit (toolchain) devShell; }
+    else determinePackageType hasBuilder toolchain;
+
+  isDerivation = value: builtins.isAttrs value && (value.type or null) == "derivation";
+
+  /**
+    Schema checks, each taking the attribute path and the value and returning a list of
+    issues found.
+   */
+  typeIssue = path: expected: value: [{
+    inherit path;
+    message = "expected ${expected}, got ${determineType value}";
+  }];
+
+  checkType = expected: isValid: path: value:
+    if isValid value then [] else typeIssue path expected value;
+
+  checkBool = checkType "a boolean" builtins.isBool;
+  checkString = checkType "a string" builtins.isString;
+  checkDerivation = checkType "a derivation" isDerivation;
+
+  isPathLike = value:
+    builtins.isString value || builtins.isPath value || isDerivation value;
+
+  checkListOf = check: path: value:
+    if builtins.isList value
+      then builtins.concatLists (builtins.genList
+        (i: check "${path}.${toString i}" (builtins.elemAt value i))
+        (builtins.length value))
+      else typeIssue path "a list" value;
+
+  checkAttrsOf = check: path: value:
+    if builtins.isAttrs value && !(isDerivation value)
+      then builtins.concatMap (name: check "${path}.${name}" value.${name}) (builtins.attrNames value)
+      else typeIssue path "an attribute set" value;
+
+  checkUnknownAttrs = known: path: value:
+    map (name: {
+      path = "${path}.${name}";
+      message = "unknown attribute of type ${determineType value.${name}}";
+    }) (builtins.filter (name: !(builtins.elem name known)) (builtins.attrNames value));
+
+  checkEnvValue = path: value:
+    if value == null || isPathLike value || builtins.isInt value || builtins.isBool value
+      then []
+    else if builtins.isAttrs value && value ? prepend
+      then checkUnknownAttrs [ "prepend" ] path value ++
+        (if builtins.isList value.prepend
+          then checkListOf (checkType "a path" isPathLike) "${path}.prepend" value.prepend
+          else checkType "a path or a list of paths" isPathLike "${path}.prepend" value.prepend)
+    else typeIssue path "a string, null or { prepend = ...; }" value;
+
+  checkEnv = checkAttrsOf checkEnvValue;
+
+  /**
+    Arguments the flake may pass to nix, with the number of values following them.
+    Everything else would allow a flake to change how nix runs beyond fetching the
+    toolchains, so it is left to NIX_RUST_WRANGLER_NIX_ARGS.
+   */
+  allowedNixArgs = {
+    "--accept-flake-config" = 0;
+    "--impure" = 0;
+    "--no-update-lock-file" = 0;
+    "--offline" = 0;
+    "--override-input" = 2;
+    "--option" = 2;
+    "--refresh" = 0;
+  };
+
+  allowedNixOptions = [
+    "substituters" "extra-substituters" "trusted-public-keys" "extra-trusted-public-keys"
+  ];
+
+  checkNixArgs = path: value:
+    let
+      count = builtins.length value;
+
+      checkFrom = i:
+        let
+          arg = builtins.elemAt value i;
+          argPath = "${path}.${toString i}";
+          arity = allowedNixArgs.${arg} or null;
+          values = builtins.genList (j: builtins.elemAt value (i + 1 + j)) arity;
+        in
+        if i >= count then []
+        else if !builtins.isString arg
+          then typeIssue argPath "a string" arg ++ checkFrom (i + 1)
+        else if arity == null
+          then [{ path = argPath; message = "nix argument ${arg} is not allowed"; }] ++ checkFrom (i + 1)
+        else if i + arity >= count
+          then [{ path = argPath; message = "nix argument ${arg} expects ${toString arity} values"; }]
+        else if !(builtins.all builtins.isString values)
+          then [{ path = argPath; message = "the values of nix argument ${arg} must be strings"; }] ++
+            checkFrom (i + 1 + arity)
+        else if arg == "--option" && !(builtins.elem (builtins.head values) allowedNixOptions)
+          then [{ path = argPath; message = "nix option ${builtins.head values} is not allowed"; }] ++
+            checkFrom (i + 1 + arity)
+        else checkFrom (i + 1 + arity);
+    in
+    if builtins.isList value then checkFrom 0 else typeIssue path "a list" value;
+
+  /**
+    Attributes of a toolchain spec, named like the keys of the toolchain table in
+    rust-toolchain.toml.
+   */
+  toolchainSpecSchema = {
+    channel = checkString;
+    components = checkListOf checkString;
+    targets = checkListOf checkString;
+    profile = checkString;
+  };
+
+  checkToolchainSpec = path: value:
+    builtins.concatMap (name:
+      if toolchainSpecSchema ? ${name}
+        then toolchainSpecSchema.${name} "${path}.${name}" value.${name}
+        else checkUnknownAttrs [] path { ${name} = value.${name}; }
+    ) (builtins.attrNames value);
+
+  checkToolchainPackage = hasBuilder: path: value:
+    if hasBuilder && isToolchainSpecSet value
+      then checkToolchainSpec path value
+    else if hasBuilder
+      then checkType "a derivation or a toolchain spec" (value: isDerivation value || isToolchainSpec value) path value
+    else checkDerivation path value;
+
+  checkToolchain = hasBuilder: path: value:
+    if builtins.isAttrs value && !(value ? type) && value ? package
+      then checkUnknownAttrs [ "package" "env" ] path value ++
+        checkToolchainPackage hasBuilder "${path}.package" value.package ++
+        (if value ? env then checkEnv "${path}.env" value.env else [])
+    else if builtins.isAttrs value && !(value ? type) && value ? devShell
+      then checkUnknownAttrs [ "devShell" ] path value ++
+        checkString "${path}.devShell" value.devShell
+    else checkToolchainPackage hasBuilder path value;
+
+  configSchema = hasBuilder: {
+    ignore = checkBool;
+    strict = checkBool;
+    mkToolchain = checkType "a function" builtins.isFunction;
+    toolchain = checkToolchain hasBuilder;
+    toolchains = checkAttrsOf (checkToolchain hasBuilder);
+    nixArgs = checkNixArgs;
+    tools = checkAttrsOf checkDerivation;
+    toolToolchain = checkAttrsOf checkString;
+    env = checkEnv;
+    buildInputs = checkListOf (checkType "a derivation or a path" isPathLike);
+    aliases = checkAttrsOf checkString;
+    directories = checkAttrsOf checkString;
+    devShell = checkString;
+    ignoreTools = checkListOf checkString;
+    passthrough = checkAttrsOf
+      (checkType ''"system" or "collection"'' (value: value == "system" || value == "collection"));
+  };
+
   /**
     Process the configuration object before it is converted to JSON.
 
     This is mainly used to prevent complete evaluation of some attributes
-    in order to reduce the amount of work done per invocation.
+    in order to reduce the amount of work done per invocation. Issues found
+    by validating the configuration are reported, and values which can't be
+    processed because of them are left out.
    */
-  processConfig = config:
-    config //
-    (if config ? toolchain then { toolchain = determineType config.toolchain; } else {}) //
-    (if config ? toolchains
+  processConfig = at: config:
+    let
+      hasBuilder = builtins.isFunction (config.mkToolchain or null);
+      schema = configSchema hasBuilder;
+
+      issuesOf = name:
+        if schema ? ${name}
+          then schema.${name} "${at}.${name}" config.${name}
+          else checkUnknownAttrs [] at { ${name} = config.${name}; };
+
+      isValid = name: config ? ${name} && issuesOf name == [];
+
+      # Values which are converted to JSON as they are
+      raw = builtins.listToAttrs (map (name: {
+        inherit name;
+        value = config.${name};
+      }) (builtins.filter isValid [
+        "ignore" "strict" "nixArgs" "toolToolchain" "aliases" "directories" "ignoreTools" "passthrough"
+        "devShell"
+      ]));
+    in
+    raw //
+    { issues = builtins.concatMap issuesOf (builtins.attrNames config); } //
+    (if config ? toolchain then { toolchain = processToolchain hasBuilder config.toolchain; } else {}) //
+    (if config ? toolchains && builtins.isAttrs config.toolchains
       then {
         toolchains = builtins.listToAttrs (map (name: {
           name = name;
-          value = determineType (config.toolchains.${name});
+          value = processToolchain hasBuilder (config.toolchains.${name});
         }) (builtins.attrNames config.toolchains));
       }
+      else {}) //
+    (if isValid "env" then { env = processEnv config.env; } else {}) //
+    (if isValid "buildInputs"
+      then { buildInputs = map processBuildInput config.buildInputs; }
+      else {}) //
+    (if config ? tools && builtins.isAttrs config.tools
+      then {
+        tools = builtins.mapAttrs (_: determineType) config.tools;
+      }
       else {});
 
 in
 outputs:
   (applyFlakeAttr outputs "devShells" (_: shells:
+    { devShells = builtins.mapAttrs (_: tryDetermineType) shells; } //
     (if shells ? default then
       { defaultDevShell = determineType shells.default; }
     else
@@ -53,7 +328,7 @@ outputs:
     {
       config = {
         at = attrPath;
-        value = processConfig rustWranglerConfig;
+        value = processConfig attrPath rustWranglerConfig;
       };
     }
   ))
@@ -1,53 +1,254 @@
 pub mod flake;
 pub mod config;
+pub mod env;
+pub mod evaluator;
+pub mod process;
+pub mod project;
 pub mod proxy;
+pub mod shell;
+pub mod version;
 
+use crate::nix::version::{NixImplementation, NixVersion};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone)]
 pub struct NixCommand {
     executable: PathBuf,
+    extra_args: Vec<OsString>,
+}
+
+/// What running the nix command revealed about it.
+#[derive(Debug)]
+struct NixProbe {
+    system: Option<String>,
     is_usable: bool,
     flakes_enabled: bool,
+
+    /// Flakes are not enabled in the nix configuration, but have been enabled for our
+    /// invocations using NIX_RUST_WRANGLER_ENABLE_FLAKES.
+    needs_experimental_features_flag: bool,
+}
+
+const EXPERIMENTAL_FEATURES_ARGS: [&str; 2] =
+    ["--extra-experimental-features", "nix-command flakes"];
+
+/// Commands printing the nix configuration, `nix show-config` was renamed to
+/// `nix config show` in Nix 2.20 and Lix only knows the former.
+const CONFIG_SHOW_COMMANDS: [&[&str]; 2] = [&["config", "show"], &["show-config"]];
+
+/// `--extra-experimental-features` is supported since this version.
+const EXTRA_EXPERIMENTAL_FEATURES_VERSION: NixVersion = NixVersion::new(2, 4, 0);
+
+/// Settings read from the nix configuration.
+#[derive(Debug, Default)]
+struct NixConfig {
+    experimental_features: Vec<String>,
+    system: Option<String>,
 }
 
+/// The probe of the nix command found in the PATH.
+///
+/// Nix is only probed once something needs to know about it, so invocations answered
+/// by the daemon, the cache or a parent invocation don't wait for nix.
+static PROBE: OnceLock<NixProbe> = OnceLock::new();
+
 impl NixCommand {
     pub fn find() -> Option<Self> {
         let executable = crate::util::find_executable_in_path("nix")?;
 
-        tracing::trace!(
-            "Running '{:?} config show experimental-features'",
-            executable
-        );
+        Some(Self {
+            executable,
+            extra_args: Self::extra_args_from_env(),
+        })
+    }
+
+    fn probe(&self) -> &'static NixProbe {
+        PROBE.get_or_init(|| NixProbe::run(&self.executable))
+    }
+
+    fn extra_args_from_env() -> Vec<OsString> {
+        let mut args = match std::env::var("NIX_RUST_WRANGLER_NIX_ARGS") {
+            Ok(args) => crate::util::split_shell_words(&args)
+                .into_iter()
+                .map(OsString::from)
+                .collect(),
+            Err(std::env::VarError::NotPresent) => Vec::new(),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                tracing::warn!("Ignoring NIX_RUST_WRANGLER_NIX_ARGS, it is not valid Unicode");
+                Vec::new()
+            }
+        };
+
+        if crate::util::flag_from_env("NIX_RUST_WRANGLER_OFFLINE") {
+            args.push(OsString::from("--offline"));
+        }
+
+        args
+    }
+
+    /// Create a copy of this command which passes additional arguments to nix.
+    pub fn with_extra_args<I, S>(&self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let mut command = self.clone();
+        command.extra_args.extend(args.into_iter().map(Into::into));
+        command
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.probe().is_usable
+    }
+
+    /// Create a command invoking a nix subcommand, such as `eval` or `build`.
+    ///
+    /// Extra arguments from NIX_RUST_WRANGLER_NIX_ARGS and the flake configuration are
+    /// appended directly after the subcommand.
+    pub fn new_command(&self, subcommand: &str) -> Command {
+        let mut command = Command::new(&self.executable);
+        command.arg(subcommand);
+        if self.probe().needs_experimental_features_flag {
+            command.args(EXPERIMENTAL_FEATURES_ARGS);
+        }
+        command.args(&self.extra_args);
+        command
+    }
+
+    pub fn executable(&self) -> &Path {
+        &self.executable
+    }
+}
+
+impl NixProbe {
+    fn run(executable: &Path) -> Self {
+        let probe = Self::detect(executable);
+
+        if probe.is_usable && probe.needs_experimental_features_flag {
+            tracing::info!(
+                "Nix is available, flakes are enabled by NIX_RUST_WRANGLER_ENABLE_FLAKES"
+            );
+        } else if probe.is_usable && probe.flakes_enabled {
+            tracing::info!("Nix is available with flakes enabled");
+        } else {
+            tracing::info!("Nix found, but it is not enabled or flakes are not enabled");
+        }
+
+        probe
+    }
 
-        // Invoke "nix config show experimental-features" to determine if flakes are enabled
-        // - this command may outright fail, if nix-command is not enabled, which also
-        //  means that flakes are not enabled (or at least not available)
-        let nix_config_output = match Command::new(&executable)
-            .args(["config", "show", "experimental-features"])
-            .output()
+    fn detect(executable: &Path) -> Self {
+        let mut probe = Self {
+            system: None,
+            is_usable: false,
+            flakes_enabled: false,
+            needs_experimental_features_flag: false,
+        };
+
+        if let Some(config) = Self::probe_config(executable, false) {
+            probe.is_usable = true;
+            probe.flakes_enabled = config.experimental_features.iter().any(|f| f == "flakes");
+            probe.system = config.system;
+        }
+
+        if probe.flakes_enabled || !crate::util::flag_from_env("NIX_RUST_WRANGLER_ENABLE_FLAKES") {
+            return probe;
+        }
+
+        // If the version can't be determined, it is assumed to be recent
+        if let Some((_, version)) = Self::probe_version(executable)
+            .filter(|(_, version)| *version < EXTRA_EXPERIMENTAL_FEATURES_VERSION)
         {
+            tracing::warn!(
+                "Nix {} is too old to enable flakes via --extra-experimental-features",
+                version
+            );
+            return probe;
+        }
+
+        // Opted in to enabling flakes ourselves, check that nix accepts the flag
+        tracing::debug!("Flakes are not enabled, retrying with --extra-experimental-features");
+        match Self::probe_config(executable, true) {
+            Some(config) => {
+                probe.is_usable = true;
+                probe.flakes_enabled = true;
+                probe.needs_experimental_features_flag = true;
+                probe.system = config.system;
+            }
+            None => tracing::warn!(
+                "Nix does not support enabling flakes via --extra-experimental-features"
+            ),
+        }
+
+        probe
+    }
+
+    /// Invoke "nix --version" to determine the implementation and version.
+    fn probe_version(executable: &Path) -> Option<(NixImplementation, NixVersion)> {
+        tracing::trace!("Running '{:?} --version'", executable);
+
+        let output = match Command::new(executable).arg("--version").output() {
             Ok(v) => v,
             Err(err) => {
-                tracing::warn!(
-                    "Failed to invoke 'nix config show experimental-features': {}",
-                    err
-                );
-                return Some(Self {
-                    executable,
-                    is_usable: false,
-                    flakes_enabled: false,
-                });
+                tracing::warn!("Failed to invoke 'nix --version': {}", err);
+                return None;
             }
         };
 
-        tracing::debug!("'nix config show experimental-features' output:");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let parsed = NixVersion::parse_version_output(&stdout);
+        match &parsed {
+            Some((implementation, version)) => {
+                tracing::debug!(
+                    "Nix implementation {:?}, version {}",
+                    implementation,
+                    version
+                )
+            }
+            None => tracing::warn!("Failed to parse 'nix --version' output: {}", stdout.trim()),
+        }
+
+        parsed
+    }
+
+    /// Read the nix configuration as JSON, trying the current command first, so recent
+    /// versions only have to be run once.
+    ///
+    /// This command may outright fail, if nix-command is not enabled, which also means that
+    /// flakes are not enabled (or at least not available). In that case `None` is returned.
+    fn probe_config(executable: &Path, enable_flakes: bool) -> Option<NixConfig> {
+        CONFIG_SHOW_COMMANDS
+            .iter()
+            .find_map(|config_args| Self::read_config(executable, config_args, enable_flakes))
+    }
+
+    fn read_config(
+        executable: &Path,
+        config_args: &[&str],
+        enable_flakes: bool,
+    ) -> Option<NixConfig> {
+        let mut command = Command::new(executable);
+        command.args(config_args);
+        if enable_flakes {
+            command.args(EXPERIMENTAL_FEATURES_ARGS);
+        }
+        command.arg("--json");
+
+        tracing::trace!("Running {:?}", command);
+
+        let nix_config_output = match command.output() {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::warn!("Failed to invoke 'nix {}': {}", config_args.join(" "), err);
+                return None;
+            }
+        };
+
+        tracing::debug!("'nix {}' output:", config_args.join(" "));
         tracing::debug!("- status: {:?}", nix_config_output.status);
-        tracing::debug!(
-            "- stdout: {}",
-            String::from_utf8_lossy(&nix_config_output.stdout)
-        );
         tracing::debug!(
             "- stderr: {}",
             String::from_utf8_lossy(&nix_config_output.stderr)
@@ -55,56 +256,135 @@ impl NixCommand {
 
         if !matches!(nix_config_output.status.code(), Some(0)) {
             // Not enabled or not available
-            return Some(Self {
-                executable,
-                is_usable: false,
-                flakes_enabled: false,
-            });
+            return None;
         }
 
-        let features = match String::from_utf8(nix_config_output.stdout) {
+        let config = match serde_json::from_slice::<serde_json::Value>(&nix_config_output.stdout) {
             Ok(v) => v,
             Err(err) => {
                 tracing::warn!(
-                    "Failed to parse 'nix config show experimental-features' output: {}",
+                    "Failed to parse 'nix {}' output: {}",
+                    config_args.join(" "),
                     err
                 );
-                return Some(Self {
-                    executable,
-                    is_usable: false,
-                    flakes_enabled: false,
-                });
+                return None;
             }
         };
 
-        let mut flakes_enabled = false;
-        for feature in features.trim().split(' ') {
-            if feature == "flakes" {
-                flakes_enabled = true;
-                break;
+        // Depending on the version, lists are either JSON arrays or space separated strings
+        let experimental_features = match &config["experimental-features"]["value"] {
+            serde_json::Value::Array(features) => features
+                .iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect(),
+            serde_json::Value::String(features) => {
+                features.split_whitespace().map(String::from).collect()
             }
-        }
+            _ => Vec::new(),
+        };
 
-        Some(Self {
-            executable,
-            is_usable: true,
-            flakes_enabled,
+        tracing::debug!("Experimental features: {:?}", experimental_features);
+
+        Some(NixConfig {
+            experimental_features,
+            system: config["system"]["value"].as_str().map(String::from),
         })
     }
+}
 
-    pub fn is_usable(&self) -> bool {
-        self.is_usable
-    }
+/// Determine the nix system string (such as `aarch64-linux`) flakes are inspected for.
+///
+/// This is NIX_RUST_WRANGLER_SYSTEM if set, which allows targeting a different system.
+/// Otherwise the system of the host is used, and nix is only asked for it if the host is
+/// not known at compile time.
+pub fn target_system() -> &'static str {
+    static SYSTEM: OnceLock<String> = OnceLock::new();
 
-    pub fn flakes_enabled(&self) -> bool {
-        self.flakes_enabled
-    }
+    SYSTEM.get_or_init(|| {
+        if let Some(system) = std::env::var("NIX_RUST_WRANGLER_SYSTEM")
+            .ok()
+            .filter(|v| !v.is_empty())
+        {
+            tracing::debug!("Using system {} from NIX_RUST_WRANGLER_SYSTEM", system);
+            return system;
+        }
+
+        if let Some(system) = host_system() {
+            return system.to_string();
+        }
+
+        match query_system() {
+            Some(system) => system,
+            None => {
+                let fallback = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+                tracing::warn!(
+                    "Unable to determine the nix system, guessing {}. \
+                    Set NIX_RUST_WRANGLER_SYSTEM to override it.",
+                    fallback
+                );
+                fallback
+            }
+        }
+    })
+}
+
+/// Map the host the wrangler was compiled for to a nix system string.
+fn host_system() -> Option<&'static str> {
+    use std::env::consts::{ARCH, OS};
 
-    pub fn new_command(&self) -> Command {
-        Command::new(&self.executable)
+    Some(match (ARCH, OS) {
+        ("x86_64", "linux") => "x86_64-linux",
+        ("aarch64", "linux") => "aarch64-linux",
+        ("x86", "linux") => "i686-linux",
+        ("riscv64", "linux") => "riscv64-linux",
+        ("x86_64", "macos") => "x86_64-darwin",
+        ("aarch64", "macos") => "aarch64-darwin",
+        ("x86_64", "freebsd") => "x86_64-freebsd",
+        _ => return None,
+    })
+}
+
+/// Ask nix for the system it builds for by default, as read when probing it.
+fn query_system() -> Option<String> {
+    NixCommand::find()?.probe().system.clone()
+}
+
+/// Format a name as a component of an attribute path, quoting it if it is not a plain
+/// identifier.
+pub fn nix_attr_name(name: &str) -> String {
+    let is_identifier = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'));
+
+    if is_identifier {
+        name.to_string()
+    } else {
+        nix_string_literal(name)
     }
-    
-    pub fn executable(&self) -> &Path {
-        &self.executable
+}
+
+/// Quote a string as a nix string literal.
+pub fn nix_string_literal(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
     }
+
+    literal.push('"');
+    literal
 }
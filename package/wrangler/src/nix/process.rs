@@ -0,0 +1,609 @@
+use crate::error::FlakeEvalError;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Arguments making nix report its activities as JSON on stderr.
+pub const LOG_FORMAT_ARGS: [&str; 2] = ["--log-format", "internal-json"];
+
+/// Minimum time between two redraws of the progress line.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+// Activity and result types of the nix logger, see nix/src/libutil/logging.hh
+const ACT_COPY_PATH: u64 = 100;
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const ACT_SUBSTITUTE: u64 = 108;
+const ACT_FETCH_TREE: u64 = 112;
+
+const RES_PROGRESS: u64 = 105;
+
+// Signal numbers, these are fixed by POSIX (see the description of `kill -s`) and the
+// same on Linux, macOS and the BSDs.
+const SIGHUP: i32 = 1;
+const SIGINT: i32 = 2;
+const SIGKILL: i32 = 9;
+const SIGTERM: i32 = 15;
+
+/// Signals which would stop us, and are forwarded to nix if it runs in its own process
+/// group.
+const FORWARDED_SIGNALS: [i32; 3] = [SIGHUP, SIGINT, SIGTERM];
+
+/// `SIG_IGN` and `SIG_ERR` of `signal`, as the handler values returned by it.
+const SIG_IGN: usize = 1;
+const SIG_ERR: usize = usize::MAX;
+
+/// Time nix gets to shut down after being asked to before it is killed forcefully.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+extern "C" {
+    /// `int killpg(pid_t pgrp, int sig)` from libc, `pid_t` is an `i32` on all supported
+    /// platforms.
+    fn killpg(pgrp: i32, sig: i32) -> i32;
+
+    /// `sighandler_t signal(int signum, sighandler_t handler)` from libc, handlers are
+    /// passed as pointer sized integers.
+    fn signal(signum: i32, handler: usize) -> usize;
+
+    /// `int raise(int sig)` from libc.
+    fn raise(sig: i32) -> i32;
+}
+
+/// Read a timeout in seconds from the environment, 0 or unset means no timeout.
+pub fn timeout_from_env(name: &str) -> Option<Duration> {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .map(Duration::from_secs)
+}
+
+/// Run a nix command, killing it if it does not finish within the timeout.
+pub fn output_with_timeout(
+    command: Command,
+    timeout: Option<Duration>,
+) -> Result<Output, FlakeEvalError> {
+    run(command, timeout, false)
+}
+
+/// Run a nix command which has been passed [LOG_FORMAT_ARGS], rendering its progress on
+/// stderr if that is a terminal.
+///
+/// The stderr of the returned output contains the messages nix would have printed without
+/// the JSON log format, so it can be used for error reporting as usual.
+pub fn output_with_progress(
+    command: Command,
+    timeout: Option<Duration>,
+) -> Result<Output, FlakeEvalError> {
+    run(command, timeout, true)
+}
+
+fn run(
+    mut command: Command,
+    timeout: Option<Duration>,
+    report_progress: bool,
+) -> Result<Output, FlakeEvalError> {
+    if timeout.is_some() {
+        // Builders and other helpers of nix need to be killed together with it. This also
+        // means nix no longer receives signals sent to the terminal's process group, so
+        // they are forwarded while it runs.
+        command.process_group(0);
+    }
+
+    let mut forwarding = timeout.map(|_| SignalForwarding::start());
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(forwarding) = &mut forwarding {
+        forwarding.forward_to(child.id());
+    }
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let child_stderr = child.stderr.take().expect("stderr is piped");
+
+    let child = Arc::new(Mutex::new(child));
+    let watchdog = timeout.map(|timeout| Watchdog::start(child.clone(), timeout));
+
+    let stdout_reader = std::thread::spawn(move || {
+        let mut data = Vec::new();
+        stdout.read_to_end(&mut data).map(|_| data)
+    });
+
+    let stderr = if report_progress {
+        read_log_events(child_stderr)
+    } else {
+        let mut data = Vec::new();
+        BufReader::new(child_stderr)
+            .read_to_end(&mut data)
+            .map(|_| data)
+    };
+
+    // Stop the watchdog and forwarding before reaping nix, its process group id can be
+    // reused afterwards
+    let timed_out = watchdog.is_some_and(Watchdog::stop);
+    drop(forwarding);
+    let status = child.lock().unwrap().wait()?;
+
+    if timed_out {
+        return Err(FlakeEvalError::Timeout {
+            timeout: timeout.unwrap_or_default(),
+        });
+    }
+
+    let stdout = stdout_reader
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("stdout reader panicked")))?;
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr: stderr?,
+    })
+}
+
+/// Read the JSON log events nix writes to stderr, rendering the progress and collecting
+/// the messages.
+fn read_log_events(child_stderr: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut progress = Progress::new(std::io::stderr().is_terminal());
+    let mut stderr = Vec::new();
+
+    for line in BufReader::new(child_stderr).split(b'\n') {
+        let line = match line {
+            Ok(v) => v,
+            Err(err) => {
+                progress.clear();
+                return Err(err);
+            }
+        };
+
+        match line.strip_prefix(b"@nix ") {
+            Some(json) => match serde_json::from_slice::<serde_json::Value>(json) {
+                Ok(event) => {
+                    if let Some(message) = progress.handle(&event) {
+                        stderr.extend_from_slice(message.as_bytes());
+                        stderr.push(b'\n');
+                    }
+                }
+                Err(err) => tracing::debug!("Failed to parse nix log event: {}", err),
+            },
+            None => {
+                stderr.extend_from_slice(&line);
+                stderr.push(b'\n');
+            }
+        }
+    }
+
+    progress.clear();
+    Ok(stderr)
+}
+
+/// Kills the process group of nix once the timeout expires.
+struct Watchdog {
+    stop: Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(child: Arc<Mutex<Child>>, timeout: Duration) -> Self {
+        let (stop, stopped) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || Self::watch(&child, timeout, stopped));
+
+        Self { stop, thread }
+    }
+
+    fn watch(child: &Mutex<Child>, timeout: Duration, stopped: Receiver<()>) -> bool {
+        if stopped.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+            return false;
+        }
+
+        // Nix may have exited right at the deadline, with its output still being read
+        let pid = {
+            let mut child = child.lock().unwrap();
+            if let Ok(Some(_)) = child.try_wait() {
+                return false;
+            }
+            child.id()
+        };
+
+        tracing::warn!(
+            "Nix did not finish within {}s, stopping it",
+            timeout.as_secs()
+        );
+        kill_process_group(pid, SIGTERM);
+
+        if stopped.recv_timeout(KILL_GRACE_PERIOD) == Err(RecvTimeoutError::Timeout) {
+            tracing::warn!("Nix did not stop in time, killing it");
+            kill_process_group(pid, SIGKILL);
+        }
+
+        true
+    }
+
+    /// Stop watching, returns whether the timeout expired.
+    fn stop(self) -> bool {
+        let _ = self.stop.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+fn kill_process_group(pid: u32, signal: i32) {
+    let Ok(pid) = i32::try_from(pid) else {
+        return;
+    };
+
+    // SAFETY: killpg has no memory safety requirements. Nix has been started as the
+    // leader of its own process group, so its pid is the id of the group.
+    if unsafe { killpg(pid, signal) } != 0 {
+        tracing::warn!(
+            "Failed to signal nix process group {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Process groups of the running nix commands signals are forwarded to, 0 marks a free
+/// slot. The signal handler can only use atomics, so this has a fixed size.
+static FORWARDING_GROUPS: [AtomicI32; 16] = [const { AtomicI32::new(0) }; 16];
+
+/// The last signal which has been forwarded, it is raised again once nix has stopped.
+static FORWARDED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Handlers replaced by the forwarding handler, while any nix command runs.
+static REPLACED_HANDLERS: Mutex<(usize, Vec<(i32, usize)>)> = Mutex::new((0, Vec::new()));
+
+extern "C" fn forward_signal(signal: i32) {
+    FORWARDED_SIGNAL.store(signal, Ordering::SeqCst);
+
+    for group in &FORWARDING_GROUPS {
+        let pgrp = group.load(Ordering::SeqCst);
+        if pgrp > 0 {
+            // SAFETY: killpg is async-signal-safe
+            unsafe { killpg(pgrp, signal) };
+        }
+    }
+}
+
+/// Forwards the signals which would stop us to the process group of nix, so stopping us
+/// stops nix as well. Once forwarding stops, a forwarded signal is raised again.
+struct SignalForwarding {
+    slot: Option<&'static AtomicI32>,
+}
+
+impl SignalForwarding {
+    /// Start handling the signals, before nix is spawned so none are missed.
+    fn start() -> Self {
+        let mut replaced = REPLACED_HANDLERS.lock().unwrap();
+        let (running, handlers) = &mut *replaced;
+
+        if *running == 0 {
+            for signum in FORWARDED_SIGNALS {
+                // SAFETY: the handler only uses atomics and killpg, which are
+                // async-signal-safe
+                let handler =
+                    unsafe { signal(signum, forward_signal as extern "C" fn(i32) as usize) };
+                match handler {
+                    // Ignored signals, like SIGHUP under nohup, stay ignored
+                    // SAFETY: restores the previous disposition
+                    SIG_IGN => unsafe {
+                        signal(signum, SIG_IGN);
+                    },
+                    SIG_ERR => {}
+                    _ => handlers.push((signum, handler)),
+                }
+            }
+        }
+        *running += 1;
+
+        Self { slot: None }
+    }
+
+    /// Forward signals to the process group nix leads.
+    fn forward_to(&mut self, pid: u32) {
+        let Ok(pgrp) = i32::try_from(pid) else {
+            return;
+        };
+
+        self.slot = FORWARDING_GROUPS.iter().find(|group| {
+            group
+                .compare_exchange(0, pgrp, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        if self.slot.is_none() {
+            tracing::debug!(
+                "Too many nix commands running, not forwarding signals to {}",
+                pid
+            );
+        }
+
+        // A signal may have arrived while nix was being spawned
+        let forwarded = FORWARDED_SIGNAL.load(Ordering::SeqCst);
+        if forwarded != 0 {
+            kill_process_group(pid, forwarded);
+        }
+    }
+}
+
+impl Drop for SignalForwarding {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            slot.store(0, Ordering::SeqCst);
+        }
+
+        let mut replaced = REPLACED_HANDLERS.lock().unwrap();
+        let (running, handlers) = &mut *replaced;
+        *running -= 1;
+        if *running > 0 {
+            return;
+        }
+
+        for (signum, handler) in handlers.drain(..) {
+            // SAFETY: restores the handler which was replaced
+            unsafe { signal(signum, handler) };
+        }
+
+        let forwarded = FORWARDED_SIGNAL.swap(0, Ordering::SeqCst);
+        if forwarded != 0 {
+            tracing::debug!("Stopping after forwarding signal {} to nix", forwarded);
+            // SAFETY: raise has no memory safety requirements
+            unsafe { raise(forwarded) };
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Activity {
+    activity_type: u64,
+    text: String,
+    done: u64,
+    expected: u64,
+}
+
+/// State of the activities reported by nix and the rendered progress line.
+struct Progress {
+    enabled: bool,
+    activities: HashMap<u64, Activity>,
+
+    /// Running activities worth showing, the most recent one last.
+    shown: Vec<u64>,
+    last_draw: Option<Instant>,
+    drawn: bool,
+}
+
+impl Progress {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            activities: HashMap::new(),
+            shown: Vec::new(),
+            last_draw: None,
+            drawn: false,
+        }
+    }
+
+    /// Process a log event, returning the message to keep if it is one.
+    fn handle(&mut self, event: &serde_json::Value) -> Option<String> {
+        let id = event["id"].as_u64().unwrap_or_default();
+
+        match event["action"].as_str()? {
+            "msg" => return event["msg"].as_str().map(String::from),
+            "start" => {
+                let activity_type = event["type"].as_u64().unwrap_or_default();
+                let text = event["text"].as_str().unwrap_or_default().to_string();
+
+                if matches!(
+                    activity_type,
+                    ACT_COPY_PATH | ACT_FILE_TRANSFER | ACT_BUILD | ACT_SUBSTITUTE | ACT_FETCH_TREE
+                ) && !text.is_empty()
+                {
+                    self.shown.push(id);
+                }
+
+                self.activities.insert(
+                    id,
+                    Activity {
+                        activity_type,
+                        text,
+                        done: 0,
+                        expected: 0,
+                    },
+                );
+            }
+            "stop" => {
+                self.activities.remove(&id);
+                self.shown.retain(|v| *v != id);
+            }
+            "result" if event["type"].as_u64() == Some(RES_PROGRESS) => {
+                if let Some(activity) = self.activities.get_mut(&id) {
+                    activity.done = event["fields"][0].as_u64().unwrap_or_default();
+                    activity.expected = event["fields"][1].as_u64().unwrap_or_default();
+                }
+            }
+            _ => {}
+        }
+
+        self.draw();
+        None
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled
+            || self
+                .last_draw
+                .is_some_and(|v| v.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+
+        let mut line = String::from("nix:");
+
+        let totals = [(ACT_BUILDS, "built"), (ACT_COPY_PATHS, "copied")]
+            .iter()
+            .filter_map(|(activity_type, verb)| {
+                self.activities
+                    .values()
+                    .find(|a| a.activity_type == *activity_type && a.expected > 0)
+                    .map(|a| format!("{}/{} {}", a.done, a.expected, verb))
+            })
+            .collect::<Vec<_>>();
+        if !totals.is_empty() {
+            line.push_str(&format!(" [{}]", totals.join(", ")));
+        }
+
+        let current = self.shown.last().and_then(|id| self.activities.get(id));
+        if totals.is_empty() && current.is_none() {
+            // Nothing worth showing yet
+            return;
+        }
+
+        if let Some(activity) = current {
+            line.push(' ');
+            line.push_str(&activity.text);
+
+            // Byte counts are only reported for transfers
+            if activity.expected > 0 && activity.activity_type != ACT_BUILD {
+                line.push_str(&format!(
+                    " ({} / {})",
+                    format_size(activity.done),
+                    format_size(activity.expected)
+                ));
+            }
+        }
+
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(80)
+            .saturating_sub(1);
+        let line = line.chars().take(width).collect::<String>();
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{}", line);
+        let _ = stderr.flush();
+
+        self.last_draw = Some(Instant::now());
+        self.drawn = true;
+    }
+
+    fn clear(&mut self) {
+        if self.drawn {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K");
+            let _ = stderr.flush();
+            self.drawn = false;
+        }
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(progress: &mut Progress, line: &str) -> Option<String> {
+        let json = line.strip_prefix("@nix ").expect("log event");
+        progress.handle(&serde_json::from_str(json).unwrap())
+    }
+
+    fn shown_texts(progress: &Progress) -> Vec<&str> {
+        progress
+            .shown
+            .iter()
+            .map(|id| progress.activities[id].text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn progress_shows_builds_and_substitutions() {
+        let mut progress = Progress::new(false);
+
+        for line in [
+            r#"@nix {"action":"start","id":1,"level":4,"parent":0,"text":"","type":104}"#,
+            r#"@nix {"action":"start","id":2,"level":3,"parent":0,"text":"building '/nix/store/abc-toolchain.drv'","type":105}"#,
+            r#"@nix {"action":"start","id":3,"level":4,"parent":0,"text":"querying info about '/nix/store/def-rustc'","type":109}"#,
+            r#"@nix {"action":"start","id":4,"level":4,"parent":0,"text":"copying path '/nix/store/def-rustc' from 'https://cache.nixos.org'","type":108}"#,
+        ] {
+            assert_eq!(handle(&mut progress, line), None);
+        }
+
+        assert_eq!(
+            shown_texts(&progress),
+            [
+                "building '/nix/store/abc-toolchain.drv'",
+                "copying path '/nix/store/def-rustc' from 'https://cache.nixos.org'",
+            ]
+        );
+
+        handle(&mut progress, r#"@nix {"action":"stop","id":4}"#);
+        assert_eq!(
+            shown_texts(&progress),
+            ["building '/nix/store/abc-toolchain.drv'"]
+        );
+    }
+
+    #[test]
+    fn progress_records_results_of_activities() {
+        let mut progress = Progress::new(false);
+
+        handle(
+            &mut progress,
+            r#"@nix {"action":"start","id":1,"level":4,"parent":0,"text":"","type":104}"#,
+        );
+        handle(
+            &mut progress,
+            r#"@nix {"action":"result","fields":[1,3,0,0],"id":1,"type":105}"#,
+        );
+
+        let builds = &progress.activities[&1];
+        assert_eq!(builds.activity_type, ACT_BUILDS);
+        assert_eq!((builds.done, builds.expected), (1, 3));
+        assert!(progress.shown.is_empty());
+    }
+
+    #[test]
+    fn progress_keeps_messages() {
+        let mut progress = Progress::new(false);
+
+        assert_eq!(
+            handle(
+                &mut progress,
+                r#"@nix {"action":"msg","level":0,"msg":"error: builder failed"}"#
+            )
+            .as_deref(),
+            Some("error: builder failed")
+        );
+    }
+
+    #[test]
+    fn format_size_uses_binary_units() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
@@ -0,0 +1,72 @@
+use crate::nix::flake::NixFlake;
+use crate::nix::shell::NixShell;
+use std::path::{Path, PathBuf};
+
+/// A nix project providing the environment for the invoked tool.
+#[derive(Debug, Clone)]
+pub enum NixProject {
+    /// A flake, which is inspected for its wrangler configuration.
+    Flake(NixFlake),
+
+    /// A legacy shell.nix or default.nix file, which is only entered as a shell.
+    Shell(NixShell),
+}
+
+impl NixProject {
+    /// Search the tree upwards from the process working directory to find a nix project.
+    /// If NIX_RUST_WRANGLER_FLAKE_PATH is set, use that as the flake path.
+    pub fn find_automatically() -> Option<Self> {
+        if let Some(flake) = NixFlake::from_env() {
+            return Some(Self::Flake(flake));
+        }
+
+        let pwd = std::env::current_dir()
+            .map_err(|err| {
+                tracing::warn!("Failed to determine current working directory: {}", err);
+                err
+            })
+            .ok()?;
+
+        if let Some(project) = Self::find(&pwd) {
+            return Some(project);
+        }
+
+        // Last resort: search upwards of our own executable
+        let exe = PathBuf::from(std::env::args_os().next()?);
+        tracing::debug!(
+            "Attempting to find nix project relative to own executable: {}",
+            exe.display()
+        );
+
+        let exe_dir = exe.parent()?;
+        Self::find(exe_dir)
+    }
+
+    /// Search the tree upwards from the given path to find a nix project.
+    ///
+    /// A flake.nix or flake marker in any parent directory is preferred over a shell.nix,
+    /// so that nested shells of a flake project don't shadow it. A default.nix is only
+    /// considered if NIX_RUST_WRANGLER_ENABLE_DEFAULT_NIX is set.
+    pub fn find(start: &Path) -> Option<Self> {
+        if let Some(flake) = start.ancestors().find_map(NixFlake::in_dir) {
+            return Some(Self::Flake(flake));
+        }
+
+        if crate::util::flag_from_env("NIX_RUST_WRANGLER_DISABLE_SHELL_NIX") {
+            return None;
+        }
+
+        let with_default_nix = crate::util::flag_from_env("NIX_RUST_WRANGLER_ENABLE_DEFAULT_NIX");
+        start
+            .ancestors()
+            .find_map(|dir| NixShell::in_dir(dir, with_default_nix))
+            .map(Self::Shell)
+    }
+
+    pub fn into_flake(self) -> Option<NixFlake> {
+        match self {
+            Self::Flake(flake) => Some(flake),
+            Self::Shell(_) => None,
+        }
+    }
+}
@@ -1,9 +1,34 @@
-use crate::invocation::Invocation;
+use crate::invocation::{Invocation, ToolchainOverride};
 use crate::invoker::ToolInvoker;
 use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+/// Set up the environment of a command which re-executes ourselves as the develop proxy
+/// inside a nix shell.
+pub fn configure_proxy_command(
+    cmd: &mut Command,
+    toolchain_override: &ToolchainOverride,
+    toolchain_for_cargo_fallback: Option<PathBuf>,
+) {
+    if let Some(toolchain_for_cargo_fallback) = toolchain_for_cargo_fallback {
+        cmd.env(
+            "NIX_RUST_WRANGLER_TOOLCHAIN_FALLBACK",
+            toolchain_for_cargo_fallback,
+        );
+    }
+
+    match toolchain_override {
+        ToolchainOverride::FromArg(name) | ToolchainOverride::FromEnv(name) => {
+            cmd.env("RUSTUP_TOOLCHAIN", name);
+            cmd.env("NIX_RUST_WRANGLER_TOOLCHAIN", name);
+        }
+        ToolchainOverride::None => {}
+    }
+
+    cmd.env("NIX_RUST_WRANGLER_INSIDE_NIX_DEVELOP", "1");
+}
+
 pub fn run_develop_proxy(invocation: Invocation) -> ! {
     if invocation.remaining_args.is_empty() {
         tracing::error!("No command to run in develop proxy");
@@ -0,0 +1,101 @@
+use crate::error::Error;
+use crate::invocation::Invocation;
+use crate::invoker::ToolInvoker;
+use crate::nix::proxy::configure_proxy_command;
+use crate::nix::NixCommand;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SHELL_FILE_NAME: &str = "shell.nix";
+
+/// Only used if enabled, as a default.nix usually builds a package instead of describing
+/// a shell.
+const DEFAULT_FILE_NAME: &str = "default.nix";
+
+/// A non-flake project, entered using its shell.nix or default.nix.
+#[derive(Debug, Clone)]
+pub struct NixShell {
+    shell_path: PathBuf,
+}
+
+impl NixShell {
+    /// Use the shell.nix inside the given directory, if there is one, or the default.nix
+    /// if `with_default_nix` is set.
+    pub fn in_dir(dir: &Path, with_default_nix: bool) -> Option<Self> {
+        let names: &[&str] = if with_default_nix {
+            &[SHELL_FILE_NAME, DEFAULT_FILE_NAME]
+        } else {
+            &[SHELL_FILE_NAME]
+        };
+
+        names.iter().find_map(|name| {
+            let shell_path = dir.join(name);
+            tracing::trace!("Checking for {} at {:?}", name, shell_path);
+
+            shell_path.is_file().then(|| {
+                tracing::debug!("Found {} at {:?}", name, shell_path);
+                Self { shell_path }
+            })
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.shell_path
+    }
+
+    /// Create an invoker which re-executes ourselves inside the shell.
+    ///
+    /// This uses `nix develop -f` if the nix command is usable, and falls back to
+    /// `nix-shell --run` otherwise.
+    pub fn make_invoker(
+        &self,
+        nix_command: &NixCommand,
+        invocation: &Invocation,
+    ) -> Result<ToolInvoker, Error> {
+        let own_executable =
+            std::env::current_exe().map_err(Error::UnableToDetermineOwnExecutable)?;
+
+        if nix_command.is_usable() {
+            let mut cmd = nix_command.new_command("develop");
+            cmd.arg("-f");
+            cmd.arg(&self.shell_path);
+            cmd.arg("--command");
+            cmd.arg(own_executable);
+            cmd.arg("nix-develop-proxy");
+
+            configure_proxy_command(&mut cmd, &invocation.toolchain_override, None);
+            return Ok(ToolInvoker::from_command_proxy(cmd, invocation));
+        }
+
+        let nix_shell = Self::find_nix_shell(nix_command).ok_or(Error::NixShellNotFound)?;
+
+        // nix-shell only accepts a single shell command, so the arguments have to be quoted
+        let mut run = crate::util::shell_quote(own_executable.as_os_str());
+        run.push(" nix-develop-proxy ");
+        run.push(crate::util::shell_quote(
+            &invocation.tool.to_executable_name(),
+        ));
+        for arg in &invocation.remaining_args {
+            run.push(" ");
+            run.push(crate::util::shell_quote(arg));
+        }
+
+        let mut cmd = Command::new(nix_shell);
+        cmd.arg(&self.shell_path);
+        cmd.arg("--run");
+        cmd.arg(run);
+
+        configure_proxy_command(&mut cmd, &invocation.toolchain_override, None);
+        Ok(ToolInvoker::from_complete_command(cmd))
+    }
+
+    /// nix-shell is installed next to nix, but may also be found in the PATH on its own.
+    fn find_nix_shell(nix_command: &NixCommand) -> Option<PathBuf> {
+        nix_command
+            .executable()
+            .parent()
+            .map(|dir| dir.join("nix-shell"))
+            .filter(|path| path.is_file())
+            .or_else(|| crate::util::find_executable_in_path("nix-shell"))
+    }
+}
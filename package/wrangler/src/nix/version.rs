@@ -0,0 +1,119 @@
+use std::fmt::{Display, Formatter};
+
+/// The nix implementation behind the `nix` executable.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NixImplementation {
+    /// The original C++ implementation, including distributions based on it.
+    CppNix,
+    Lix,
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NixVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl NixVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse the output of `nix --version`.
+    ///
+    /// Examples are `nix (Nix) 2.18.1`, `nix (Lix, like Nix) 2.91.1` and
+    /// `nix (Nix) 2.25.0pre20241010_dirty`.
+    pub fn parse_version_output(output: &str) -> Option<(NixImplementation, Self)> {
+        let output = output.lines().next()?.trim();
+
+        let implementation = match output
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split_once(')'))
+        {
+            Some((name, _)) if name.contains("Lix") => NixImplementation::Lix,
+            Some((name, _)) if name.contains("Nix") => NixImplementation::CppNix,
+            Some((name, _)) => NixImplementation::Unknown(name.to_string()),
+            None => NixImplementation::Unknown(output.to_string()),
+        };
+
+        let version = Self::parse(output.rsplit(' ').next()?)?;
+        Some((implementation, version))
+    }
+
+    /// Parse a version such as `2.18.1`, ignoring any suffix after the numeric part.
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut components = version.split('.').map(|component| {
+            let digits = component
+                .find(|c: char| !c.is_ascii_digit())
+                .map(|end| &component[..end])
+                .unwrap_or(component);
+            digits.parse::<u32>().ok()
+        });
+
+        let major = components.next()??;
+        let minor = components.next().flatten().unwrap_or(0);
+        let patch = components.next().flatten().unwrap_or(0);
+
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl Display for NixVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version_output_detects_implementation() {
+        assert_eq!(
+            NixVersion::parse_version_output("nix (Nix) 2.18.1\n"),
+            Some((NixImplementation::CppNix, NixVersion::new(2, 18, 1)))
+        );
+        assert_eq!(
+            NixVersion::parse_version_output("nix (Lix, like Nix) 2.91.1"),
+            Some((NixImplementation::Lix, NixVersion::new(2, 91, 1)))
+        );
+        assert_eq!(
+            NixVersion::parse_version_output("nix (Determinate Systems) 3.1.0"),
+            Some((
+                NixImplementation::Unknown("Determinate Systems".to_string()),
+                NixVersion::new(3, 1, 0)
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_version_output_ignores_suffixes() {
+        assert_eq!(
+            NixVersion::parse_version_output("nix (Nix) 2.25.0pre20241010_dirty"),
+            Some((NixImplementation::CppNix, NixVersion::new(2, 25, 0)))
+        );
+        assert_eq!(
+            NixVersion::parse_version_output("nix (Nix) 2.4\nmore output"),
+            Some((NixImplementation::CppNix, NixVersion::new(2, 4, 0)))
+        );
+    }
+
+    #[test]
+    fn parse_version_output_rejects_garbage() {
+        assert_eq!(NixVersion::parse_version_output(""), None);
+        assert_eq!(NixVersion::parse_version_output("nix (Nix) unknown"), None);
+    }
+
+    #[test]
+    fn versions_are_ordered() {
+        assert!(NixVersion::new(2, 4, 0) < NixVersion::new(2, 20, 0));
+        assert!(NixVersion::new(2, 20, 1) > NixVersion::new(2, 20, 0));
+    }
+}
@@ -1,5 +1,8 @@
 use std::ffi::{OsStr, OsString};
+use std::hash::Hasher;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 pub fn prepend_paths<I, S>(current: Option<OsString>, new: I) -> OsString
 where
@@ -24,15 +27,192 @@ pub fn u32_from_env(name: &str) -> u32 {
         .unwrap_or(0)
 }
 
-pub fn was_dispatched_into_flake() -> bool {
-    std::env::var_os("NIX_RUST_WRANGLER_INSIDE_NIX_DEVELOP")
-        .map(|v| v.len() > 0)
+/// Check whether an environment variable is set to a non-empty value.
+pub fn flag_from_env(name: &str) -> bool {
+    std::env::var_os(name)
+        .map(|v| !v.is_empty())
         .unwrap_or(false)
 }
 
+pub fn was_dispatched_into_flake() -> bool {
+    flag_from_env("NIX_RUST_WRANGLER_INSIDE_NIX_DEVELOP")
+}
+
 pub fn find_executable_in_path(name: impl AsRef<OsStr>) -> Option<PathBuf> {
     let path = std::env::var_os("PATH")?;
     std::env::split_paths(&path)
         .map(|p| p.join(name.as_ref()))
         .find(|p| p.is_file())
 }
+
+/// Find an executable in PATH which is not the wrangler itself.
+///
+/// The wrangler is usually installed in PATH under the names of the tools, so the first
+/// match would just invoke ourselves again.
+pub fn find_foreign_executable_in_path(name: impl AsRef<OsStr>) -> Option<PathBuf> {
+    let own_executable = std::env::current_exe()
+        .and_then(|v| v.canonicalize())
+        .ok()?;
+
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|p| p.join(name.as_ref()))
+        .filter(|p| p.is_file())
+        .find(|p| p.canonicalize().is_ok_and(|v| v != own_executable))
+}
+
+/// Quote a string for use as a single word in a POSIX shell.
+pub fn shell_quote(word: &OsStr) -> OsString {
+    let bytes = word.as_bytes();
+    let is_plain = !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./=+:,@%".contains(b));
+    if is_plain {
+        return word.to_os_string();
+    }
+
+    let mut quoted = Vec::with_capacity(bytes.len() + 2);
+    quoted.push(b'\'');
+    for b in bytes {
+        if *b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(*b);
+        }
+    }
+    quoted.push(b'\'');
+
+    OsString::from_vec(quoted)
+}
+
+/// Split a string into words, honoring single quotes, double quotes and backslash
+/// escapes the way a POSIX shell does (without any expansion).
+pub fn split_shell_words(input: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                current.extend(chars.by_ref().take_while(|c| *c != '\''));
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(escaped @ ('"' | '\\' | '$' | '`')) => current.push(escaped),
+                            Some(other) => {
+                                current.push('\\');
+                                current.push(other);
+                            }
+                            None => current.push('\\'),
+                        },
+                        c => current.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(current);
+    }
+
+    words
+}
+
+/// Seconds since the unix epoch, saturating at 0 for clocks set before 1970.
+pub fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// 64 bit FNV-1a hasher.
+///
+/// Unlike the std `DefaultHasher`, the output of this hasher is stable across
+/// Rust versions, which makes it suitable for keys that are persisted to disk.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub fn finish_hex(&self) -> String {
+        format!("{:016x}", self.finish())
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_shell_words;
+
+    #[test]
+    fn split_shell_words_splits_on_whitespace() {
+        assert_eq!(
+            split_shell_words("  --impure\t--option  substituters \n"),
+            ["--impure", "--option", "substituters"]
+        );
+        assert!(split_shell_words("   ").is_empty());
+    }
+
+    #[test]
+    fn split_shell_words_honors_quotes() {
+        assert_eq!(
+            split_shell_words(r#"--option substituters 'https://a https://b' "x y""#),
+            ["--option", "substituters", "https://a https://b", "x y"]
+        );
+        assert_eq!(split_shell_words(r#"a''b "" ''"#), ["ab", "", ""]);
+        assert_eq!(split_shell_words(r#"'a"b' "a'b""#), [r#"a"b"#, "a'b"]);
+    }
+
+    #[test]
+    fn split_shell_words_handles_escapes() {
+        assert_eq!(split_shell_words(r"a\ b \'c"), ["a b", "'c"]);
+        assert_eq!(split_shell_words(r#""\"\\\$\`" "\n""#), ["\"\\$`", "\\n"]);
+        assert_eq!(split_shell_words(r"'\n'"), [r"\n"]);
+    }
+}